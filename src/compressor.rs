@@ -1,46 +1,319 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::sync::mpsc;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::result::Result;
 use std::fs;
-use crate::ffmpeg::compressor::{CompressorOptions, FFmpegCompressor};
+use human_repr::HumanCount;
+use crate::ffmpeg::analysis::SourceAnalysis;
+use crate::ffmpeg::compressor::{CompressionOutcome, CompressorOptions, FFmpegCompressor};
 use crate::error::{CompressorError, InputParseError};
 use crate::ffmpeg::parameter_factories::av1::Av1ParameterFactory;
 use crate::ffmpeg::parameter_factories::hevc::HevcParameterFactory;
+use crate::ffmpeg::parameter_factories::filters::VideoFilters;
 use crate::ffmpeg::parameter_factories::ParameterFactory;
+use crate::ffmpeg::crf_search::CrfSearch;
+use crate::ffmpeg::dolby_vision::DolbyVisionHandling;
+use crate::ffmpeg::probe::AVProbeMetadata;
+use crate::ffmpeg::probe_cache::ProbeCache;
+use crate::ffmpeg::quality::measure_scores;
+use crate::ffmpeg::sampler::FFmpegSampler;
+use crate::fstools::{copy_file_metadata, move_file};
+use crate::ledger::{FileReport, Ledger, Verdict};
+
+/// Where the transcoded file for an input should be written.
+#[derive(Clone, Debug)]
+pub enum OutputLocation {
+    /// `movie.mkv` becomes `movie.av1.mkv` in the same directory.
+    BesideInput,
+    /// Exactly this path.
+    File(PathBuf),
+    /// The generated filename, but in this directory.
+    Directory(PathBuf),
+}
+
+/// The codecs we have parameter factories for.
+pub const SUPPORTED_CODECS: [&str; 2] = ["av1", "hevc"];
+
+/// A file's encoder parameters and the analysis they were built from.
+type Prepared = (Box<dyn ParameterFactory>, SourceAnalysis);
 
 pub struct Compressor {
     options: CompressorOptions,
     events: Rc<RefCell<mpsc::Receiver<bool>>>,
+    probe_cache: Rc<RefCell<ProbeCache>>,
+    ledger: RefCell<Ledger>,
+    reports: RefCell<Vec<(PathBuf, FileReport)>>,
+    stopped: Cell<bool>,
 }
 
 impl Compressor {
-    pub fn new(options: CompressorOptions, events: Rc<RefCell<mpsc::Receiver<bool>>>) -> Self {
+    pub fn new(options: CompressorOptions, events: Rc<RefCell<mpsc::Receiver<bool>>>, probe_cache: ProbeCache, ledger: Ledger) -> Self {
         Compressor {
             events,
+            probe_cache: Rc::new(RefCell::new(probe_cache)),
+            ledger: RefCell::new(ledger),
+            reports: RefCell::new(Vec::new()),
+            stopped: Cell::new(false),
             options,
         }
     }
 
-    pub fn compress_file(&self, input: &PathBuf, _output: &PathBuf) -> Result<(), CompressorError> {
-        if let Ok(parameters) = create_parameter_factory(input, &self.options) {
-            let compressor = FFmpegCompressor::new(self.options.clone(), Rc::clone(&self.events));
-            let output = self.generate_output_filename(&input);
-            match fs::exists(&output) {
-                Ok(exists) => {
-                    if exists {
-                        println!("{:?} already exists; skipping {:?}", output, input);
-                    } else {
-                        compressor.compress(input, &output, &parameters)?;
+    /// Whether we've been asked to stop, either mid-transcode or since.
+    /// Once stopped, nothing more should be started.
+    pub fn stopped(&self) -> bool {
+        if !self.stopped.get()
+            && let Ok(rx) = self.events.try_borrow_mut()
+            && let Ok(true) = rx.try_recv() {
+            println!("Caught stop signal.");
+            self.stopped.set(true);
+        }
+        self.stopped.get()
+    }
+
+    /// `relative_dir` is where `input` sits in the tree being walked; it's
+    /// mirrored beneath --archive-dir.
+    pub fn compress_file(&self, input: &PathBuf, output: &OutputLocation, relative_dir: &Path) -> Result<(), CompressorError> {
+        let output = self.output_filename(input, output)?;
+        if self.options.sample {
+            let Some((parameters, _)) = self.prepare(input)? else {
+                return Ok(());
+            };
+            let sampler = FFmpegSampler::new(self.options.clone(), Rc::clone(&self.probe_cache));
+            return sampler.sample(input, &output, self.search_crf(input, parameters).as_ref());
+        }
+        match fs::exists(&output) {
+            Ok(exists) => {
+                if exists {
+                    println!("{:?} already exists; skipping {:?}", output, input);
+                } else {
+                    if let Some(entry) = self.ledger.borrow().lookup(input)
+                        && entry.report.rules_out(&self.options.codec)
+                        && !self.options.retry_rejected {
+                        println!("{:?} was found not worth compressing with {} before; skipping", input, entry.report.codec);
+                        return Ok(());
+                    }
+                    let Some((parameters, analysis)) = self.prepare(input)? else {
+                        return Ok(());
+                    };
+                    let outcome = self.compress_with_retries(input, &output, self.search_crf(input, parameters))?;
+                    if outcome == CompressionOutcome::Stopped {
+                        self.stopped.set(true);
+                        return Ok(());
                     }
-                    Ok(())
+                    self.record(input, &output, &outcome, &analysis);
+                    if self.options.replace && matches!(outcome, CompressionOutcome::Compressed | CompressionOutcome::DryRun) {
+                        self.replace_original(input, &output, relative_dir)?;
+                    }
+                }
+                Ok(())
+            },
+            Err(err) => Err(CompressorError::for_file(&output, &format!("Error determining if {:?} exists.\n{:?}", output, err))),
+        }
+    }
+
+    /// Analyse `input` once so every encode of it (samples, CRF search,
+    /// retries) shares the same decisions, and build its parameters. `None`
+    /// when the analysis says to leave the file alone.
+    fn prepare(&self, input: &PathBuf) -> Result<Option<Prepared>, CompressorError> {
+        // leave reporting probe failures and skipping to the compressor
+        let probe = self.probe_cache.borrow_mut().probe(input, self.options.count_frames)
+            .unwrap_or_else(|_| AVProbeMetadata::empty());
+        let analysis = match probe.video_codec == self.options.codec || probe.video_codec.is_empty() {
+            true => SourceAnalysis::default(),
+            false => SourceAnalysis::analyze(input, &probe, &self.options),
+        };
+        if let DolbyVisionHandling::Skip(reason) = &analysis.dolby_vision {
+            println!("Skipping {:?}; {}.", input, reason);
+            return Ok(None);
+        }
+        match create_parameter_factory(input, &self.options, &analysis, &probe) {
+            Ok(parameters) => Ok(Some((parameters, analysis))),
+            Err(_) => Err(CompressorError::for_file(input, &format!("Unable to create {} compressor.", self.options.codec))),
+        }
+    }
+
+    /// With a quality target, replace the configured CRF with the one whose
+    /// samples just reach it.
+    fn search_crf(&self, input: &PathBuf, parameters: Box<dyn ParameterFactory>) -> Box<dyn ParameterFactory> {
+        let Some(target) = &self.options.target_quality else {
+            return parameters;
+        };
+        if self.options.dry_run {
+            println!("dry-run mode; skipping CRF search for {}", target);
+            return parameters;
+        }
+        // leave reporting probe failures and skipping to the compressor
+        let probe = match self.probe_cache.borrow_mut().probe(input, self.options.count_frames) {
+            Ok(probe) if probe.video_codec != self.options.codec => probe,
+            _ => return parameters,
+        };
+        match CrfSearch::new(self.options.clone()).search(input, &probe, parameters.as_ref(), target) {
+            Ok(crf) => parameters.with_crf(crf),
+            Err(err) => {
+                println!("{}; keeping crf {}", err, parameters.crf());
+                parameters
+            },
+        }
+    }
+
+    /// With a target ratio, attempts that miss it are abandoned (using the
+    /// same projection as --max-size-ratio) and retried at a higher CRF until
+    /// one fits, the attempts run out, or the CRF would pass the quality
+    /// floor. The last attempt is held only to --max-size-ratio.
+    fn compress_with_retries(&self, input: &PathBuf, output: &Path, parameters: Box<dyn ParameterFactory>) -> Result<CompressionOutcome, CompressorError> {
+        let mut parameters = parameters;
+        let max_crf = self.options.max_crf.unwrap_or(parameters.max_crf()).min(parameters.max_crf());
+        let mut attempt = 1;
+        loop {
+            let next_crf = next_attempt_crf(parameters.crf(), attempt, self.options.target_ratio.is_some(),
+                self.options.max_attempts, self.options.crf_step, max_crf);
+            let last_attempt = next_crf.is_none();
+            let mut options = self.options.clone();
+            if let (Some(target_ratio), false) = (self.options.target_ratio, last_attempt) {
+                options.max_size_ratio = target_ratio;
+            }
+
+            let compressor = FFmpegCompressor::new(options, Rc::clone(&self.events), Rc::clone(&self.probe_cache));
+            let outcome = compressor.compress(input, output, parameters.as_ref())?;
+            if self.options.target_ratio.is_some() {
+                match &outcome {
+                    CompressionOutcome::NotWorthCompressing { input_size, output_size } => println!(
+                        "attempt {} at crf {}: {} of {} ({:.1}%){}",
+                        attempt, parameters.crf(),
+                        output_size.human_count_bytes(), input_size.human_count_bytes(),
+                        100.0 * *output_size as f64 / *input_size as f64,
+                        if last_attempt { "; giving up" } else { "; retrying" }),
+                    CompressionOutcome::Compressed => println!("attempt {} at crf {}: kept", attempt, parameters.crf()),
+                    _ => {},
+                }
+            }
+            match (outcome, next_crf) {
+                (CompressionOutcome::NotWorthCompressing { .. }, Some(next_crf)) => {
+                    parameters = parameters.with_crf(next_crf);
+                    attempt += 1;
                 },
-                Err(err) => Err(CompressorError::for_file(&output, &format!("Error determining if {:?} exists.\n{:?}", output, err))),
+                (outcome, _) => return Ok(outcome),
+            }
+        }
+    }
+
+    /// Note what became of `input` in the ledger and this run's summary,
+    /// scoring kept outputs against their originals when asked to.
+    fn record(&self, input: &Path, output: &Path, outcome: &CompressionOutcome, analysis: &SourceAnalysis) {
+        let (verdict, input_size, output_size) = match outcome {
+            CompressionOutcome::Compressed => (Verdict::Compressed, file_size(input), file_size(output)),
+            CompressionOutcome::NotWorthCompressing { input_size, output_size } =>
+                (Verdict::NotWorthCompressing, *input_size as u64, *output_size as u64),
+            _ => return,
+        };
+        let probe = self.probe_cache.borrow_mut().probe(&input.to_path_buf(), self.options.count_frames).ok();
+        let duration = probe.as_ref().and_then(|probe| probe.duration()).filter(|duration| *duration > 0.0);
+        let bit_rate = |size: u64| duration.map(|duration| (size as f64 * 8.0 / duration) as u64);
+        let metrics = match (&probe, verdict) {
+            (Some(probe), Verdict::Compressed) if self.options.metrics => {
+                println!("measuring quality of {:?}", output);
+                let filters = VideoFilters::new(&self.options, analysis);
+                match measure_scores(input, output, filters.reference_size(probe), &filters.reference_chain(probe)) {
+                    Ok(scores) => {
+                        println!("{}", scores);
+                        Some(scores)
+                    },
+                    Err(err) => {
+                        println!("Unable to measure quality of {:?}; {}", output, err);
+                        None
+                    },
+                }
+            },
+            _ => None,
+        };
+
+        let report = FileReport {
+            codec: self.options.codec.clone(),
+            verdict,
+            input_size,
+            output_size,
+            input_bit_rate: bit_rate(input_size),
+            output_bit_rate: bit_rate(output_size),
+            metrics,
+        };
+        self.ledger.borrow_mut().record(input, &report);
+        self.reports.borrow_mut().push((input.to_path_buf(), report));
+    }
+
+    /// Save what's worth remembering for the next run.
+    pub fn flush(&self) {
+        self.probe_cache.borrow_mut().flush();
+    }
+
+    /// Print what this run did with each file it transcoded.
+    pub fn print_summary(&self) {
+        let reports = self.reports.borrow();
+        if reports.is_empty() {
+            return;
+        }
+        println!("summary:");
+        for (input, report) in reports.iter() {
+            let mut line = format!("  {:?}: {}, {} -> {} ({:.1}%)",
+                input,
+                match report.verdict {
+                    Verdict::Compressed => "compressed",
+                    Verdict::NotWorthCompressing => "not worth compressing",
+                },
+                report.input_size.human_count_bytes(),
+                report.output_size.human_count_bytes(),
+                100.0 * report.output_size as f64 / report.input_size.max(1) as f64);
+            if let (Some(input_bit_rate), Some(output_bit_rate)) = (report.input_bit_rate, report.output_bit_rate) {
+                line.push_str(&format!(", {} -> {}", input_bit_rate.human_count("b/s"), output_bit_rate.human_count("b/s")));
+            }
+            if let Some(metrics) = &report.metrics {
+                line.push_str(&format!(", {}", metrics));
+            }
+            println!("{}", line);
+        }
+        let compressed: Vec<&FileReport> = reports.iter()
+            .map(|(_, report)| report)
+            .filter(|report| report.verdict == Verdict::Compressed)
+            .collect();
+        let saved: u64 = compressed.iter().map(|report| report.input_size.saturating_sub(report.output_size)).sum();
+        println!("compressed {} of {} files, saving {}", compressed.len(), reports.len(), saved.human_count_bytes());
+    }
+
+    /// Swap a verified output in for its original, which is either moved to
+    /// the archive directory or deleted.
+    fn replace_original(&self, input: &PathBuf, output: &Path, relative_dir: &Path) -> Result<(), CompressorError> {
+        let (final_path, archive_path) = replacement_paths(input, output, self.options.keep_name, &self.options.container,
+            self.options.archive_dir.as_deref(), relative_dir)?;
+        if self.options.dry_run {
+            match &archive_path {
+                Some(archive) => println!("dry-run mode; would move {:?} to {:?} and {:?} to {:?}", input, archive, output, final_path),
+                None => println!("dry-run mode; would delete {:?} and move {:?} to {:?}", input, output, final_path),
             }
-        } else {
-            Err(CompressorError::for_file(input, &format!("Unable to create {} compressor.", self.options.codec)))
+            return Ok(());
+        }
+        replace(input, output, &final_path, archive_path.as_deref())
+    }
+
+    fn output_filename(&self, input: &PathBuf, location: &OutputLocation) -> Result<PathBuf, CompressorError> {
+        let output = match location {
+            OutputLocation::BesideInput => self.generate_output_filename(input),
+            OutputLocation::File(file) => file.clone(),
+            OutputLocation::Directory(dir) => {
+                if !self.options.dry_run {
+                    fs::create_dir_all(dir)
+                        .map_err(|err| CompressorError::for_file(input, &format!("Unable to create output directory {:?}; {}", dir, err)))?;
+                }
+                let generated = self.generate_output_filename(input);
+                match generated.file_name() {
+                    Some(name) => dir.join(name),
+                    None => return Err(CompressorError::for_file(input, "Unable to generate an output filename.")),
+                }
+            },
+        };
+        if output == *input || (output.exists() && fs::canonicalize(&output).ok() == fs::canonicalize(input).ok()) {
+            return Err(CompressorError::for_file(input, "Refusing to write output over the input file."));
         }
+        Ok(output)
     }
 
     fn generate_output_filename(&self, path: &PathBuf) -> PathBuf {
@@ -56,14 +329,185 @@ impl Compressor {
     }
 }
 
-fn create_parameter_factory(input: &PathBuf, options: &CompressorOptions) -> Result<Box<dyn ParameterFactory>, InputParseError> {
+fn create_parameter_factory(input: &PathBuf, options: &CompressorOptions, analysis: &SourceAnalysis, probe: &AVProbeMetadata) -> Result<Box<dyn ParameterFactory>, InputParseError> {
     match options.codec.as_str() {
-        "av1" => Ok(Box::new(Av1ParameterFactory::new(options))),
-        "hevc" => Ok(Box::new(HevcParameterFactory::new(options))),
+        "av1" => Ok(Box::new(Av1ParameterFactory::new(options, analysis, probe))),
+        "hevc" => Ok(Box::new(HevcParameterFactory::new(options, analysis, probe))),
         _ => Err(InputParseError::for_file(input, &format!("Unsupported output codec: {}.", options.codec))),
     }
 }
 
+/// The CRF to retry at should `attempt` miss --target-ratio, or `None` when
+/// it's the last: without a target, once the attempts run out, or when the
+/// next step would pass `max_crf`.
+fn next_attempt_crf(crf: u16, attempt: u32, retrying: bool, max_attempts: u32, crf_step: u16, max_crf: u16) -> Option<u16> {
+    match retrying && attempt < max_attempts {
+        true => Some(crf.saturating_add(crf_step)).filter(|next| *next <= max_crf),
+        false => None,
+    }
+}
+
+/// The name the replacement ends up with and, when archiving, where the
+/// original goes. Archived originals keep their place in the tree, so
+/// `Show A/S01E01.mkv` and `Show B/S01E01.mkv` don't collide.
+fn replacement_paths(input: &PathBuf, output: &Path, keep_name: bool, container: &str, archive_dir: Option<&Path>, relative_dir: &Path) -> Result<(PathBuf, Option<PathBuf>), CompressorError> {
+    let final_path = match keep_name {
+        true => input.with_extension(container),
+        false => match output.file_name() {
+            Some(name) => input.with_file_name(name),
+            None => return Err(CompressorError::for_file(input, "Unable to determine replacement filename.")),
+        },
+    };
+    let archive_path = match archive_dir {
+        Some(dir) => match input.file_name() {
+            Some(name) => Some(dir.join(relative_dir).join(name)),
+            None => return Err(CompressorError::for_file(input, "Unable to determine archive filename.")),
+        },
+        None => None,
+    };
+    Ok((final_path, archive_path))
+}
+
+fn replace(input: &PathBuf, output: &Path, final_path: &Path, archive_path: Option<&Path>) -> Result<(), CompressorError> {
+    // never clobber anything other than the original itself
+    if final_path != input.as_path() && final_path != output && final_path.exists() {
+        return Err(CompressorError::for_file(input, &format!("{:?} already exists; keeping the original.", final_path)));
+    }
+    if let Some(archive) = archive_path && archive.exists() {
+        return Err(CompressorError::for_file(input, &format!("{:?} already exists; keeping the original.", archive)));
+    }
+
+    copy_file_metadata(input, output)
+        .map_err(|err| CompressorError::for_file(input, &format!("Unable to copy file metadata to {:?}; {}", output, err)))?;
+    // put the transcode in place before the original goes anywhere; when it
+    // takes the original's name, the rename replaces it atomically instead
+    let takes_original_name = final_path == input.as_path();
+    if !takes_original_name && final_path != output {
+        rename_output(input, output, final_path)?;
+    }
+    match archive_path {
+        Some(archive) => {
+            if let Err(err) = fs::create_dir_all(archive.parent().unwrap_or(Path::new("."))) {
+                return Err(CompressorError::for_file(input, &format!("Unable to create archive directory; {}", err)));
+            }
+            println!("archiving {:?} to {:?}", input, archive);
+            move_file(input, archive)
+                .map_err(|err| CompressorError::for_file(input, &format!("Unable to archive original; {}", err)))?;
+        },
+        None if !takes_original_name => {
+            println!("deleting {:?}", input);
+            fs::remove_file(input)
+                .map_err(|err| CompressorError::for_file(input, &format!("Unable to delete original; {}", err)))?;
+        },
+        None => {},
+    }
+    if takes_original_name {
+        rename_output(input, output, final_path)?;
+    }
+    println!("replaced {:?} with {:?}", input, final_path);
+    Ok(())
+}
+
+fn rename_output(input: &PathBuf, output: &Path, final_path: &Path) -> Result<(), CompressorError> {
+    fs::rename(output, final_path)
+        .map_err(|err| CompressorError::for_file(input, &format!("Unable to rename {:?} to {:?}; {}", output, final_path, err)))
+}
+
+fn file_size(path: &Path) -> u64 {
+    fs::metadata(path).map(|m| m.len()).unwrap_or(0)
+}
+
 fn extension(codec: &str, container: &str) -> String {
     format!("{codec:}.{container:}")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fstools::ScratchDir;
+
+    fn replace_in(dir: &Path, input: &str, keep_name: bool, archive_dir: Option<&Path>, relative_dir: &Path) -> Result<PathBuf, CompressorError> {
+        let input = dir.join(input);
+        let output = input.with_file_name(format!("{}.av1.mkv", input.file_stem().unwrap().to_string_lossy()));
+        fs::write(&input, "original").unwrap();
+        fs::write(&output, "transcode").unwrap();
+        let (final_path, archive_path) = replacement_paths(&input, &output, keep_name, "mkv", archive_dir, relative_dir)?;
+        replace(&input, &output, &final_path, archive_path.as_deref()).map(|_| final_path)
+    }
+
+    #[test]
+    fn test_next_attempt_crf() {
+        assert_eq!(next_attempt_crf(25, 1, false, 3, 4, 63), None);
+        assert_eq!(next_attempt_crf(25, 1, true, 3, 4, 63), Some(29));
+        assert_eq!(next_attempt_crf(29, 2, true, 3, 4, 63), Some(33));
+        assert_eq!(next_attempt_crf(33, 3, true, 3, 4, 63), None);
+        assert_eq!(next_attempt_crf(60, 1, true, 3, 4, 63), None);
+        assert_eq!(next_attempt_crf(60, 1, true, 3, u16::MAX, 63), None);
+    }
+
+    #[test]
+    fn test_replace_deletes_original() {
+        let dir = ScratchDir::new("replace-delete");
+        let final_path = replace_in(&dir, "movie.mkv", false, None, Path::new("")).unwrap();
+        assert_eq!(final_path, dir.join("movie.av1.mkv"));
+        assert!(!dir.join("movie.mkv").exists());
+        assert_eq!(fs::read_to_string(&final_path).unwrap(), "transcode");
+    }
+
+    #[test]
+    fn test_replace_keeps_name() {
+        let dir = ScratchDir::new("replace-keep-name");
+        let final_path = replace_in(&dir, "movie.mkv", true, None, Path::new("")).unwrap();
+        assert_eq!(final_path, dir.join("movie.mkv"));
+        assert!(!dir.join("movie.av1.mkv").exists());
+        assert_eq!(fs::read_to_string(&final_path).unwrap(), "transcode");
+
+        // an mp4 becomes an mkv of the same name, and the mp4 goes
+        let final_path = replace_in(&dir, "clip.mp4", true, None, Path::new("")).unwrap();
+        assert_eq!(final_path, dir.join("clip.mkv"));
+        assert!(!dir.join("clip.mp4").exists());
+    }
+
+    #[test]
+    fn test_replace_archives_original() {
+        let dir = ScratchDir::new("replace-archive");
+        let archive = dir.join("archive");
+        for show in ["Show A", "Show B"] {
+            fs::create_dir_all(dir.join("tv").join(show)).unwrap();
+            let relative_dir = Path::new("tv").join(show);
+            replace_in(&dir.join("tv").join(show), "S01E01.mkv", false, Some(&archive), &relative_dir).unwrap();
+            assert_eq!(fs::read_to_string(archive.join("tv").join(show).join("S01E01.mkv")).unwrap(), "original");
+            assert_eq!(fs::read_to_string(dir.join("tv").join(show).join("S01E01.av1.mkv")).unwrap(), "transcode");
+            assert!(!dir.join("tv").join(show).join("S01E01.mkv").exists());
+        }
+    }
+
+    #[test]
+    fn test_replace_refuses_to_clobber() {
+        let dir = ScratchDir::new("replace-clobber");
+        fs::write(dir.join("clip.mkv"), "someone else's").unwrap();
+        assert!(replace_in(&dir, "clip.mp4", true, None, Path::new("")).is_err());
+        assert_eq!(fs::read_to_string(dir.join("clip.mkv")).unwrap(), "someone else's");
+        assert_eq!(fs::read_to_string(dir.join("clip.mp4")).unwrap(), "original");
+
+        let archive = dir.join("archive");
+        fs::create_dir_all(&archive).unwrap();
+        fs::write(archive.join("movie.mkv"), "archived before").unwrap();
+        assert!(replace_in(&dir, "movie.mkv", false, Some(&archive), Path::new("")).is_err());
+        assert_eq!(fs::read_to_string(archive.join("movie.mkv")).unwrap(), "archived before");
+        assert_eq!(fs::read_to_string(dir.join("movie.mkv")).unwrap(), "original");
+        assert_eq!(fs::read_to_string(dir.join("movie.av1.mkv")).unwrap(), "transcode");
+    }
+
+    #[test]
+    fn test_replace_keeps_original_when_rename_fails() {
+        let dir = ScratchDir::new("replace-rename-fails");
+        let input = dir.join("movie.mkv");
+        let output = dir.join("movie.av1.mkv");
+        fs::write(&input, "original").unwrap();
+        fs::write(&output, "transcode").unwrap();
+        assert!(replace(&input, &output, &dir.join("missing").join("movie.av1.mkv"), None).is_err());
+        assert_eq!(fs::read_to_string(&input).unwrap(), "original");
+        assert_eq!(fs::read_to_string(&output).unwrap(), "transcode");
+    }
+}
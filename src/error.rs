@@ -2,19 +2,55 @@ use std::error::Error;
 use std::fmt::Display;
 use std::path::PathBuf;
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InputParseReason {
+    /// The file could not be opened or ffprobe does not understand it.
+    Unreadable,
+    /// The file ends before its container says it should.
+    Truncated,
+    /// The file contains no (non cover-art) video stream.
+    NoVideoStream,
+    /// The video stream uses a codec ffprobe can't identify.
+    UnknownCodec,
+    /// Anything else, e.g. ffprobe output we couldn't make sense of.
+    Invalid,
+}
+
+impl Display for InputParseReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InputParseReason::Unreadable => write!(f, "unreadable"),
+            InputParseReason::Truncated => write!(f, "truncated"),
+            InputParseReason::NoVideoStream => write!(f, "no video stream"),
+            InputParseReason::UnknownCodec => write!(f, "unknown codec"),
+            InputParseReason::Invalid => write!(f, "invalid"),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct InputParseError {
     path: PathBuf,
+    reason: InputParseReason,
     msg: String,
 }
 
 impl InputParseError {
     pub fn for_file(path: &PathBuf, msg: &str) -> Self {
+        InputParseError::with_reason(path, InputParseReason::Invalid, msg)
+    }
+
+    pub fn with_reason(path: &PathBuf, reason: InputParseReason, msg: &str) -> Self {
         InputParseError {
             path: PathBuf::from(path),
+            reason,
             msg: String::from(msg),
         }
     }
+
+    pub fn reason(&self) -> InputParseReason {
+        self.reason
+    }
 }
 
 impl Error for InputParseError {
@@ -33,7 +69,7 @@ impl Error for InputParseError {
 
 impl Display for InputParseError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Error parsing {:?}: {}", &self.path, &self.msg)
+        write!(f, "Error parsing {:?} ({}): {}", &self.path, &self.reason, &self.msg)
     }
 }
 
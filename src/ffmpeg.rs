@@ -1,8 +1,20 @@
+use std::path::PathBuf;
 use std::process::Command;
+pub mod analysis;
 pub mod compressor;
+pub mod content;
+pub mod crf_search;
+pub mod cropdetect;
+pub mod dolby_vision;
+pub mod grain;
 pub mod probe;
+pub mod probe_cache;
+pub mod quality;
+pub mod sampler;
+pub mod verifier;
 pub mod parameter_factories;
 
+#[derive(Default)]
 pub struct FFmpeg {
 }
 
@@ -21,3 +33,8 @@ impl FFmpeg {
         }
     }
 }
+
+/// Render an ffmpeg invocation the way a user could paste it into a shell.
+pub fn describe_command(args: &[PathBuf]) -> String {
+    format!("ffmpeg {}", args.iter().map(|s| format!("{:?}", s)).collect::<Vec<String>>().join(" "))
+}
@@ -0,0 +1,86 @@
+use std::path::Path;
+use crate::ffmpeg::compressor::CompressorOptions;
+use crate::ffmpeg::content::{classify, ContentMode, ContentType};
+use crate::ffmpeg::cropdetect::{detect_crop, Crop, CropMode};
+use crate::ffmpeg::grain::{detect_film_grain, FilmGrainMode};
+use crate::ffmpeg::dolby_vision::{decide, libx265_supports_dolby_vision, DolbyVisionHandling};
+use crate::ffmpeg::probe::AVProbeMetadata;
+
+/// What we learn about a source by looking at its pictures rather than its
+/// metadata. It's worked out once per file and shared by every encode of it,
+/// whether sample, CRF search or retry.
+#[derive(Clone, Debug, Default)]
+pub struct SourceAnalysis {
+    pub crop: Option<Crop>,
+    pub dolby_vision: DolbyVisionHandling,
+    /// SVT-AV1 film-grain level; only AV1 can synthesise grain.
+    pub film_grain: Option<u8>,
+    pub content: ContentType,
+}
+
+impl SourceAnalysis {
+    pub fn analyze(input: &Path, probe: &AVProbeMetadata, options: &CompressorOptions) -> Self {
+        let dolby_vision = match probe.video().and_then(|video| video.dolby_vision) {
+            Some(dolby_vision) => decide(&dolby_vision, options.dolby_vision, &options.codec, libx265_supports_dolby_vision),
+            None => DolbyVisionHandling::None,
+        };
+        match &dolby_vision {
+            DolbyVisionHandling::Skip(_) => return SourceAnalysis {
+                dolby_vision,
+                ..SourceAnalysis::default()
+            },
+            DolbyVisionHandling::BaseLayer => println!("warning: encoding only the base layer of {:?}; its Dolby Vision metadata will be lost", input),
+            DolbyVisionHandling::KeepRpu => println!("keeping the Dolby Vision RPU of {:?}", input),
+            DolbyVisionHandling::None => {},
+        }
+
+        let content = match options.content {
+            ContentMode::Fixed(content) => content,
+            ContentMode::Auto => classify(input, probe),
+        };
+        if content == ContentType::Animation {
+            println!("tuning {:?} for animation", input);
+        }
+
+        let crop = match options.crop {
+            CropMode::Off => None,
+            CropMode::Manual(crop) => Some(crop),
+            CropMode::Auto => match detect_crop(input, probe) {
+                Ok(crop) => crop,
+                Err(err) => {
+                    println!("Unable to detect black bars in {:?}; {}", input, err);
+                    None
+                },
+            },
+        };
+        match crop {
+            Some(crop) => println!("cropping {:?} from {}x{} to {}", input, probe.width, probe.height, crop),
+            None => println!("not cropping {:?}", input),
+        }
+
+        // drawn lines and flat fills have no grain worth synthesising
+        let film_grain = match (options.film_grain, options.codec.as_str()) {
+            (_, codec) if codec != "av1" => None,
+            (FilmGrainMode::Off, _) => None,
+            (FilmGrainMode::Level(level), _) => Some(level),
+            (FilmGrainMode::Auto, _) if content == ContentType::Animation => None,
+            (FilmGrainMode::Auto, _) => match detect_film_grain(input, probe) {
+                Ok(level) => {
+                    println!("film grain level {} for {:?}", level, input);
+                    Some(level)
+                },
+                Err(err) => {
+                    println!("Unable to measure film grain in {:?}; {}", input, err);
+                    None
+                },
+            },
+        };
+
+        SourceAnalysis {
+            crop,
+            dolby_vision,
+            film_grain,
+            content,
+        }
+    }
+}
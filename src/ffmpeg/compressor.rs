@@ -1,12 +1,25 @@
 use std::cell::RefCell;
-use std::{io::{BufRead, BufReader, Read}, path::PathBuf, process::{Child, ChildStderr, Command, Stdio}};
+use std::{io::{BufRead, BufReader, Read}, path::{Path, PathBuf}, process::{Child, ChildStderr, Command, Stdio}};
 use std::fs;
 use std::rc::Rc;
 use std::sync::mpsc;
 use kdam::{term, tqdm, BarExt};
 use human_repr::HumanCount;
 use crate::error::CompressorError;
-use crate::ffmpeg::probe::probe_file;
+use crate::ffmpeg::content::ContentMode;
+use crate::ffmpeg::cropdetect::CropMode;
+use crate::ffmpeg::describe_command;
+use crate::ffmpeg::dolby_vision::DolbyVisionPolicy;
+use crate::ffmpeg::grain::FilmGrainMode;
+use crate::fstools::{partial_path, remove_if_exists};
+use crate::ffmpeg::probe::{AVProbeMetadata, FrameCountSource};
+use crate::ffmpeg::probe_cache::ProbeCache;
+use crate::ffmpeg::quality::QualityTarget;
+use crate::ffmpeg::verifier::{FFmpegVerifier, StreamSelection};
+use super::parameter_factories::crf_table::CrfTable;
+use super::parameter_factories::quality_preset::QualityPreset;
+use super::parameter_factories::{merge_arguments, EncoderOverrides};
+use super::parameter_factories::filters::{Deinterlace, Scale, Tonemap};
 use super::parameter_factories::ParameterFactory;
 
 
@@ -15,6 +28,7 @@ struct CompressionProgress {
     pub frame: usize,
     pub fps: f64,
     pub total_size: usize,
+    pub out_time_us: u64,
 }
 
 impl CompressionProgress {
@@ -23,10 +37,47 @@ impl CompressionProgress {
             frame: 0,
             fps: 0.0,
             total_size: 0,
+            out_time_us: 0,
+        }
+    }
+
+    /// How many source frames have been transcoded. When the frame count was
+    /// only estimated the encoded timestamp is a better measure than ffmpeg's
+    /// frame counter, as the estimate is derived from the duration too.
+    /// `frame_rate_factor` is how many output frames each source frame
+    /// becomes, e.g. 2 when deinterlacing to the field rate.
+    pub fn position(&self, probe: &AVProbeMetadata, frame_rate_factor: usize) -> usize {
+        match probe.frame_count_source {
+            FrameCountSource::Estimated if self.out_time_us > 0 => {
+                let position = (self.out_time_us as f64 / 1_000_000.0 * probe.exact_frame_rate()) as usize;
+                position.min(probe.total_frames)
+            },
+            _ => self.frame / frame_rate_factor,
         }
     }
 }
 
+#[derive(Debug, PartialEq, Eq)]
+pub enum CompressionOutcome {
+    /// A new, verified output was written.
+    Compressed,
+    /// The input was left alone, e.g. it is already in the target codec.
+    Skipped,
+    /// The transcode was (or was projected to be) too large to be worth keeping.
+    NotWorthCompressing { input_size: usize, output_size: usize },
+    DryRun,
+    /// A stop signal arrived mid-transcode and ffmpeg was killed.
+    Stopped,
+}
+
+enum TranscodeResult {
+    Finished,
+    /// Stopped early because the projected size wasn't worth it.
+    Abandoned { projected_size: usize },
+    /// Killed because we were asked to stop.
+    Stopped,
+}
+
 enum FFmpegStdoutResult {
     Continue,
     Render,
@@ -35,49 +86,80 @@ enum FFmpegStdoutResult {
 #[derive(Clone, Debug)]
 pub struct CompressorOptions {
     pub dry_run: bool,
-    pub fast: bool,
-    pub extreme: bool,
+    pub sample: bool,
+    pub sample_count: usize,
+    pub sample_length: u64,
+    pub quality: QualityPreset,
     pub overwrite: bool,
+    pub count_frames: bool,
     pub codec: String,
     pub container: String,
+    pub max_size_ratio: f64,
+    pub abort_after: f64,
+    pub retry_rejected: bool,
+    pub target_quality: Option<QualityTarget>,
+    pub fallback_quality: QualityTarget,
+    pub target_ratio: Option<f64>,
+    pub max_attempts: u32,
+    pub crf_step: u16,
+    pub max_crf: Option<u16>,
+    pub verify: bool,
+    pub verify_tolerance: f64,
+    pub metrics: bool,
+    pub deinterlace: Deinterlace,
+    pub crop: CropMode,
+    pub dolby_vision: DolbyVisionPolicy,
+    pub tonemap: Option<Tonemap>,
+    pub scale: Scale,
+    pub film_grain: FilmGrainMode,
+    pub film_grain_denoise: bool,
+    pub content: ContentMode,
+    pub crf_table: Option<CrfTable>,
+    pub overrides: EncoderOverrides,
+    pub replace: bool,
+    pub keep_name: bool,
+    pub archive_dir: Option<PathBuf>,
 }
 
 pub struct FFmpegCompressor {
     events: Rc<RefCell<mpsc::Receiver<bool>>>,
+    probe_cache: Rc<RefCell<ProbeCache>>,
     options: CompressorOptions,
 }
 
 impl FFmpegCompressor {
-    pub fn new(options: CompressorOptions, events: Rc<RefCell<mpsc::Receiver<bool>>>) -> Self {
+    pub fn new(options: CompressorOptions, events: Rc<RefCell<mpsc::Receiver<bool>>>, probe_cache: Rc<RefCell<ProbeCache>>) -> Self {
         FFmpegCompressor {
             events,
+            probe_cache,
             options,
         }
     }
 
-    pub fn compress(&self, input: &PathBuf, output: &PathBuf, parameters: &Box<dyn ParameterFactory>) -> Result<(), CompressorError> {
-        match probe_file(input) {
+    pub fn compress(&self, input: &PathBuf, output: &Path, parameters: &dyn ParameterFactory) -> Result<CompressionOutcome, CompressorError> {
+        let probe = self.probe_cache.borrow_mut().probe(input, self.options.count_frames);
+        match probe {
             Ok(probe) => {
                 if probe.video_codec == self.options.codec {
                     println!("{:?} is already encoded with {}; skipping", input, self.options.codec);
-                    return Ok(())
+                    return Ok(CompressionOutcome::Skipped)
                 }
-                let total_frames = probe.total_frames;
-                let input_size = get_file_size(input);
                 let mut args = vec![
                     PathBuf::from("-i"), PathBuf::from(input),
                 ];
-                for param in parameters.parameters(input, &probe) {
-                    args.push(param);
-                }
-                args.push(PathBuf::from("-c:a"));
-                args.push(PathBuf::from("copy"));
-                args.push(PathBuf::from("-c:s"));
-                args.push(PathBuf::from("copy"));
-                args.push(PathBuf::from("-map"));
-                args.push(PathBuf::from("0"));
-                args.push(output.clone());
-                println!("ffmpeg {}", args.iter().map(|s| format!("{:?}", s)).collect::<Vec<String>>().join(" "));
+                let mut output_args = parameters.parameters(input, &probe);
+                output_args.push(PathBuf::from("-c:a"));
+                output_args.push(PathBuf::from("copy"));
+                output_args.push(PathBuf::from("-c:s"));
+                output_args.push(PathBuf::from("copy"));
+                output_args.push(PathBuf::from("-map"));
+                output_args.push(PathBuf::from("0"));
+                args.extend(merge_arguments(output_args, &self.options.overrides.ffmpeg_args));
+                args.push(PathBuf::from("-f"));
+                args.push(PathBuf::from(muxer(&self.options.container)));
+                let partial = partial_path(output);
+                args.push(partial.clone());
+                println!("{}", describe_command(&args));
 
                 // insert our pipe processing magic after showing the user the ffmpeg
                 // command in case they want to copypasta it for use on their own
@@ -88,72 +170,129 @@ impl FFmpegCompressor {
                 args.insert(0, PathBuf::from("-nostats"));
                 args.insert(0, PathBuf::from("-hide_banner"));
                 if !self.options.dry_run {
-                    if let Ok(mut child) = Command::new("ffmpeg")
-                        .args(args)
-                        .stdout(Stdio::piped())
-                        .stderr(Stdio::piped())
-                        .spawn() {
-
-                        term::init(false);
-
-                        let mut pbar = tqdm!(
-                            total = total_frames,
-                            desc = format!("transcoding {}", input_size.human_count_bytes()),
-                            position = 0,
-                            force_refresh = true
-                        );
-                        let mut progress = CompressionProgress::new();
-                        let stdout = child.stdout.take().unwrap();
-                        let stdout_reader = BufReader::new(stdout);
-                        for line in stdout_reader.lines() {
-                            if let Ok(l) = line {
-                                match self.handle_ffmpeg_stdout_line(l, &mut progress) {
-                                    FFmpegStdoutResult::Continue => continue,
-                                    FFmpegStdoutResult::Render => {
-                                        pbar.set_postfix(format!("{} ({})",
-                                            progress.total_size.human_count_bytes(),
-                                            predict_compressed_size(progress.total_size, total_frames, progress.frame).human_count_bytes()));
-                                        let _ = pbar.update_to(progress.frame);
-                                    },
-                                }
-                            }
-
-                            self.check_for_stop(&mut child);
-                        }
-
-                        println!("Waiting for ffmpeg to exit.");
-                        if let Ok(status) = child.wait() {
-                            match status.success() {
-                                true => Ok(()),
-                                false => {
-                                    if let Some(stderr) = read_stderr_to_end(&mut child.stderr.take()) {
-                                        print!("{}", stderr);
-                                    }
-                                    if let Some(code) = status.code() {
-                                        Err(CompressorError::for_file(input, &format!("ffmpeg exited with {:}", code)))
-                                    } else {
-                                        Err(CompressorError::for_file(input, "ffmpeg did not exit successfully."))
-                                    }
-                                },
-                            }
-                        } else {
-                            Err(CompressorError::for_file(input, "There was an error waiting for the ffmpeg process."))
-                        }
-                    } else {
-                        Err(CompressorError::for_file(input, "There was an error executing ffmpeg."))
+                    // a partial left behind by an earlier crash is never worth keeping
+                    if let Err(err) = remove_if_exists(&partial) {
+                        return Err(CompressorError::for_file(input, &format!("Unable to remove stale partial {:?}; {}", partial, err)));
+                    }
+                    let frame_rate_factor = parameters.filters().frame_rate_factor(&probe);
+                    let result = self.transcode(input, args, &probe, frame_rate_factor)
+                        .and_then(|result| self.keep_or_discard(input, &probe, frame_rate_factor, &partial, output, result));
+                    if !matches!(result, Ok(CompressionOutcome::Compressed)) && let Err(err) = remove_if_exists(&partial) {
+                        println!("Unable to remove {:?}; {}", partial, err);
                     }
+                    result
                 } else {
                     println!("dry-run mode; skipping transcode operation");
-                    Ok(())
+                    Ok(CompressionOutcome::DryRun)
                 }
             },
             Err(err) => {
-                println!("Probe failed for {:?}; {:?}.\nSkipping {:?}.", input, err, input);
-                Ok(())
+                println!("{}.\nSkipping {:?}.", err, input);
+                Ok(CompressionOutcome::Skipped)
             }
         }
     }
 
+    /// Decide what becomes of a finished (or abandoned) transcode: outputs
+    /// that don't save enough space are discarded, the rest are verified and
+    /// renamed into place.
+    fn keep_or_discard(&self, input: &PathBuf, probe: &AVProbeMetadata, frame_rate_factor: usize, partial: &PathBuf, output: &Path, result: TranscodeResult) -> Result<CompressionOutcome, CompressorError> {
+        let input_size = get_file_size(input);
+        let output_size = match result {
+            TranscodeResult::Abandoned { projected_size } => {
+                println!("{:?} is projected to be {} of {}; abandoned transcode.",
+                    input, projected_size.human_count_bytes(), input_size.human_count_bytes());
+                return Ok(CompressionOutcome::NotWorthCompressing { input_size, output_size: projected_size });
+            },
+            TranscodeResult::Stopped => return Ok(CompressionOutcome::Stopped),
+            TranscodeResult::Finished => get_file_size(partial),
+        };
+        if exceeds_size_ratio(output_size, input_size, self.options.max_size_ratio) {
+            println!("{:?} transcoded to {} of {}; discarding it.",
+                input, output_size.human_count_bytes(), input_size.human_count_bytes());
+            return Ok(CompressionOutcome::NotWorthCompressing { input_size, output_size });
+        }
+
+        FFmpegVerifier::new(&self.options.codec, self.options.verify, self.options.verify_tolerance,
+            StreamSelection::from_args(&self.options.overrides.ffmpeg_args))
+            .verify(input, probe, partial, frame_rate_factor)?;
+        fs::rename(partial, output)
+            .map(|_| CompressionOutcome::Compressed)
+            .map_err(|err| CompressorError::for_file(input, &format!("Unable to rename {:?} to {:?}; {}", partial, output, err)))
+    }
+
+    fn transcode(&self, input: &PathBuf, args: Vec<PathBuf>, probe: &AVProbeMetadata, frame_rate_factor: usize) -> Result<TranscodeResult, CompressorError> {
+        let total_frames = probe.total_frames;
+        let input_size = get_file_size(input);
+        if let Ok(mut child) = Command::new("ffmpeg")
+            .args(args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn() {
+
+            term::init(false);
+
+            let mut pbar = tqdm!(
+                total = total_frames,
+                desc = format!("transcoding {}", input_size.human_count_bytes()),
+                position = 0,
+                force_refresh = true
+            );
+            let mut progress = CompressionProgress::new();
+            let stdout = child.stdout.take().unwrap();
+            let stdout_reader = BufReader::new(stdout);
+            for line in stdout_reader.lines() {
+                if let Ok(l) = line {
+                    match self.handle_ffmpeg_stdout_line(l, &mut progress) {
+                        FFmpegStdoutResult::Continue => continue,
+                        FFmpegStdoutResult::Render => {
+                            let position = progress.position(probe, frame_rate_factor);
+                            let projected_size = predict_compressed_size(progress.total_size, total_frames, position);
+                            pbar.set_postfix(format!("{} ({})",
+                                progress.total_size.human_count_bytes(),
+                                projected_size.human_count_bytes()));
+                            let _ = pbar.update_to(position);
+
+                            if (position as f64) >= (total_frames as f64) * self.options.abort_after / 100.0
+                                && exceeds_size_ratio(projected_size, input_size, self.options.max_size_ratio) {
+                                println!();
+                                let _ = child.kill();
+                                let _ = child.wait();
+                                return Ok(TranscodeResult::Abandoned { projected_size });
+                            }
+                        },
+                    }
+                }
+
+                if self.check_for_stop(&mut child) {
+                    let _ = child.wait();
+                    return Ok(TranscodeResult::Stopped);
+                }
+            }
+
+            println!("Waiting for ffmpeg to exit.");
+            if let Ok(status) = child.wait() {
+                match status.success() {
+                    true => Ok(TranscodeResult::Finished),
+                    false => {
+                        if let Some(stderr) = read_stderr_to_end(&mut child.stderr.take()) {
+                            print!("{}", stderr);
+                        }
+                        if let Some(code) = status.code() {
+                            Err(CompressorError::for_file(input, &format!("ffmpeg exited with {:}", code)))
+                        } else {
+                            Err(CompressorError::for_file(input, "ffmpeg did not exit successfully."))
+                        }
+                    },
+                }
+            } else {
+                Err(CompressorError::for_file(input, "There was an error waiting for the ffmpeg process."))
+            }
+        } else {
+            Err(CompressorError::for_file(input, "There was an error executing ffmpeg."))
+        }
+    }
+
     fn handle_ffmpeg_stdout_line(&self, line: String, progress: &mut CompressionProgress) -> FFmpegStdoutResult {
         let parts: Vec<&str> = line.split('=').collect();
         if parts.len() == 2 {
@@ -170,6 +309,10 @@ impl FFmpegCompressor {
                     progress.total_size = parts[1].parse().unwrap_or(progress.total_size);
                     FFmpegStdoutResult::Continue
                 },
+                "out_time_us" => {
+                    progress.out_time_us = parts[1].parse().unwrap_or(progress.out_time_us);
+                    FFmpegStdoutResult::Continue
+                },
                 "progress" => FFmpegStdoutResult::Render,
                 _ => FFmpegStdoutResult::Continue,
             }
@@ -178,19 +321,19 @@ impl FFmpegCompressor {
         }
     }
 
-    fn check_for_stop(&self, child: &mut Child) {
-        if let Ok(rx) = self.events.try_borrow_mut() {
-            if let Ok(stop) = rx.try_recv() {
-                if stop {
-                    println!("Caught stop signal; killing ffmpeg!");
-                    if let Err(err) = child.kill() {
-                        println!("error killing ffmpeg process ({}) {err:?}", child.id());
-                    } else {
-                        println!("killed ffmpeg process ({})", child.id());
-                    }
-                }
+    /// Kill ffmpeg if a stop signal has arrived, returning whether it did.
+    fn check_for_stop(&self, child: &mut Child) -> bool {
+        if let Ok(rx) = self.events.try_borrow_mut()
+            && let Ok(true) = rx.try_recv() {
+            println!("Caught stop signal; killing ffmpeg!");
+            if let Err(err) = child.kill() {
+                println!("error killing ffmpeg process ({}) {err:?}", child.id());
+            } else {
+                println!("killed ffmpeg process ({})", child.id());
             }
+            return true;
         }
+        false
     }
 }
 
@@ -198,16 +341,23 @@ fn read_stderr_to_end(stderr: &mut Option<ChildStderr>) -> Option<String> {
     let mut buf = Vec::new();
     match stderr {
         Some(stream) => match BufReader::new(stream).read_to_end(&mut buf) {
-            Ok(_) => match String::from_utf8(buf) {
-                Ok(s) => Some(s),
-                Err(_) => None,
-            },
+            Ok(_) => String::from_utf8(buf).ok(),
             Err(_) => None,
         },
         None => None,
     }
 }
 
+/// The muxer for a container name, needed because partial files don't carry
+/// an extension ffmpeg could guess it from.
+fn muxer(container: &str) -> &str {
+    match container {
+        "mkv" => "matroska",
+        "m4v" => "mp4",
+        other => other,
+    }
+}
+
 fn get_file_size(input: &PathBuf) -> usize {
     match fs::metadata(input) {
         Ok(fi) => fi.len().try_into().unwrap_or(1),
@@ -215,6 +365,10 @@ fn get_file_size(input: &PathBuf) -> usize {
     }
 }
 
+fn exceeds_size_ratio(output_size: usize, input_size: usize, max_size_ratio: f64) -> bool {
+    (output_size as f64) > (input_size as f64) * max_size_ratio
+}
+
 fn predict_compressed_size(
     compressed_size: usize,
     total_frames: usize,
@@ -0,0 +1,63 @@
+use std::path::Path;
+use crate::ffmpeg::probe::AVProbeMetadata;
+
+/// Words in a path or genre tag that mark a title as animated.
+const ANIMATION_WORDS: [&str; 6] = ["anime", "animation", "animated", "cartoon", "cartoons", "animes"];
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ContentType {
+    #[default]
+    LiveAction,
+    Animation,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContentMode {
+    Auto,
+    Fixed(ContentType),
+}
+
+impl ContentMode {
+    pub fn parse(mode: &str) -> Option<Self> {
+        match mode.to_lowercase().as_str() {
+            "auto" => Some(ContentMode::Auto),
+            "live-action" => Some(ContentMode::Fixed(ContentType::LiveAction)),
+            "animation" => Some(ContentMode::Fixed(ContentType::Animation)),
+            _ => None,
+        }
+    }
+}
+
+/// Animation if the title's genre tag or any directory or file name on its
+/// path says so; live action otherwise.
+pub fn classify(input: &Path, probe: &AVProbeMetadata) -> ContentType {
+    let genre = probe.format.tags.iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case("genre"))
+        .map(|(_, genre)| genre.as_str())
+        .unwrap_or_default();
+    match mentions_animation(genre) || mentions_animation(&input.to_string_lossy()) {
+        true => ContentType::Animation,
+        false => ContentType::LiveAction,
+    }
+}
+
+fn mentions_animation(text: &str) -> bool {
+    text.split(|c: char| !c.is_alphanumeric())
+        .any(|word| ANIMATION_WORDS.iter().any(|animation| word.eq_ignore_ascii_case(animation)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        let mut probe = AVProbeMetadata::empty();
+        assert_eq!(classify(Path::new("/media/Anime/Cowboy Bebop/S01E01.mkv"), &probe), ContentType::Animation);
+        assert_eq!(classify(Path::new("/media/movies/Animal House (1978).mkv"), &probe), ContentType::LiveAction);
+        assert_eq!(classify(Path::new("/media/tv/saturday-morning-cartoons/ep1.mkv"), &probe), ContentType::Animation);
+
+        probe.format.tags.insert(String::from("GENRE"), String::from("Family / Animation"));
+        assert_eq!(classify(Path::new("/media/movies/Up (2009).mkv"), &probe), ContentType::Animation);
+    }
+}
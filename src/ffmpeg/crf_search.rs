@@ -0,0 +1,139 @@
+use std::env;
+use std::path::PathBuf;
+use std::process::{self, Command};
+use crate::error::CompressorError;
+use crate::ffmpeg::compressor::CompressorOptions;
+use crate::ffmpeg::describe_command;
+use crate::ffmpeg::probe::AVProbeMetadata;
+use crate::ffmpeg::quality::{has_libvmaf, measure, QualityMetric, QualityTarget};
+use crate::ffmpeg::sampler::sample_points;
+use crate::fstools::remove_if_exists;
+use super::parameter_factories::ParameterFactory;
+
+/// Finds the CRF whose sample clips just reach a quality target, so grainy
+/// films get the bits they need and cartoons don't get more than they need.
+pub struct CrfSearch {
+    options: CompressorOptions,
+}
+
+impl CrfSearch {
+    pub fn new(options: CompressorOptions) -> Self {
+        CrfSearch {
+            options,
+        }
+    }
+
+    pub fn search(&self, input: &PathBuf, probe: &AVProbeMetadata, parameters: &dyn ParameterFactory, target: &QualityTarget) -> Result<u16, CompressorError> {
+        let target = match (target.metric, has_libvmaf()) {
+            (QualityMetric::Vmaf, false) => {
+                let fallback = self.options.fallback_quality;
+                println!("ffmpeg was built without libvmaf; searching for {} instead of {}", fallback, target);
+                fallback
+            },
+            _ => *target,
+        };
+        let Some(duration) = probe.duration() else {
+            return Err(CompressorError::for_file(input, "unable to determine duration for CRF search."));
+        };
+        let length = self.options.sample_length as f64;
+        let starts = sample_points(duration, self.options.sample_count, length);
+
+        // walk up from the best quality candidate; once a CRF misses the
+        // target we have the bracket we need
+        let mut points = Vec::new();
+        for crf in candidate_crfs(parameters.crf(), self.options.crf_step, parameters.max_crf()) {
+            let candidate = parameters.with_crf(crf);
+            let mut scores = Vec::new();
+            for start in &starts {
+                scores.push(self.score_clip(input, probe, candidate.as_ref(), *start, length, target.metric)?);
+            }
+            let score = scores.iter().sum::<f64>() / scores.len() as f64;
+            println!("crf {}: {} {:.3}", crf, target.metric, score);
+            points.push((crf, score));
+            if score < target.score {
+                break;
+            }
+        }
+
+        let crf = interpolate_crf(&points, target.score).unwrap_or(parameters.crf());
+        println!("using crf {} for {} {}", crf, target.metric, target.score);
+        Ok(crf)
+    }
+
+    fn score_clip(&self, input: &PathBuf, probe: &AVProbeMetadata, parameters: &dyn ParameterFactory, start: f64, length: f64, metric: QualityMetric) -> Result<f64, CompressorError> {
+        let clip = env::temp_dir().join(format!("compress-mkv-{}-crf{}-{:.0}.mkv", process::id(), parameters.crf(), start));
+        let mut args = vec![
+            PathBuf::from("-hide_banner"),
+            PathBuf::from("-nostats"),
+            PathBuf::from("-loglevel"), PathBuf::from("error"),
+            PathBuf::from("-y"),
+            PathBuf::from("-ss"), PathBuf::from(format!("{:.3}", start)),
+            PathBuf::from("-i"), input.clone(),
+            PathBuf::from("-t"), PathBuf::from(format!("{:.3}", length)),
+            PathBuf::from("-map"), PathBuf::from("0:v:0"),
+        ];
+        // search clips are video only, so --ffmpeg-args (which may map or
+        // encode other streams) are left out
+        args.extend(parameters.parameters(input, probe));
+        args.extend([PathBuf::from("-an"), PathBuf::from("-sn"), clip.clone()]);
+
+        let encoded = Command::new("ffmpeg").args(&args).status();
+        let result = match encoded {
+            Ok(status) if status.success() => measure(input, &clip, &[metric], parameters.filters().reference_size(probe), Some((start, length)),
+                    &parameters.filters().reference_chain(probe))
+                .map(|scores| scores[0])
+                .map_err(|err| CompressorError::for_file(input, &err)),
+            _ => Err(CompressorError::for_file(input, &format!("unable to encode CRF search clip: {}", describe_command(&args)))),
+        };
+        let _ = remove_if_exists(&clip);
+        result
+    }
+}
+
+/// Candidates two steps either side of the configured CRF, best quality first.
+fn candidate_crfs(crf: u16, step: u16, max_crf: u16) -> Vec<u16> {
+    (-2..=2)
+        .map(|k| crf as i32 + k * step as i32)
+        .filter(|crf| *crf >= 1 && *crf <= max_crf as i32)
+        .map(|crf| crf as u16)
+        .collect()
+}
+
+/// Scores fall as CRF rises; find the pair of measurements that brackets the
+/// target and interpolate between them, rounding toward better quality.
+fn interpolate_crf(points: &[(u16, f64)], target: f64) -> Option<u16> {
+    let (first, last) = (points.first()?, points.last()?);
+    if first.1 < target {
+        return Some(first.0);
+    }
+    if last.1 >= target {
+        return Some(last.0);
+    }
+    points.windows(2)
+        .find(|pair| pair[0].1 >= target && pair[1].1 < target)
+        .map(|pair| {
+            let ((crf_a, score_a), (crf_b, score_b)) = (pair[0], pair[1]);
+            let fraction = (score_a - target) / (score_a - score_b);
+            (crf_a as f64 + fraction * (crf_b - crf_a) as f64).floor() as u16
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_candidate_crfs() {
+        assert_eq!(candidate_crfs(25, 4, 63), vec![17, 21, 25, 29, 33]);
+        assert_eq!(candidate_crfs(5, 4, 63), vec![1, 5, 9, 13]);
+        assert_eq!(candidate_crfs(49, 4, 51), vec![41, 45, 49]);
+    }
+
+    #[test]
+    fn test_interpolate_crf() {
+        assert_eq!(interpolate_crf(&[(17, 97.0), (21, 95.5), (25, 93.5)], 95.0), Some(22));
+        assert_eq!(interpolate_crf(&[(17, 94.0)], 95.0), Some(17));
+        assert_eq!(interpolate_crf(&[(17, 99.0), (21, 98.0)], 95.0), Some(21));
+        assert_eq!(interpolate_crf(&[], 95.0), None);
+    }
+}
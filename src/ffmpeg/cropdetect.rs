@@ -0,0 +1,150 @@
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use crate::ffmpeg::probe::AVProbeMetadata;
+use crate::ffmpeg::sampler::sample_points;
+
+const SAMPLE_COUNT: usize = 10;
+const SAMPLE_LENGTH: f64 = 2.0;
+
+/// A crop rectangle, as ffmpeg's `crop` filter takes it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Crop {
+    pub width: u64,
+    pub height: u64,
+    pub x: u64,
+    pub y: u64,
+}
+
+impl Crop {
+    /// Parse `W:H:X:Y`, the form `cropdetect` reports after `crop=`.
+    pub fn parse(crop: &str) -> Option<Self> {
+        let parts: Vec<u64> = crop.split(':').map(|part| part.trim().parse().ok()).collect::<Option<_>>()?;
+        match parts[..] {
+            [width, height, x, y] if width > 0 && height > 0 => Some(Crop { width, height, x, y }),
+            _ => None,
+        }
+    }
+
+    pub fn filter(&self) -> String {
+        format!("crop={}:{}:{}:{}", self.width, self.height, self.x, self.y)
+    }
+
+    fn area(&self) -> u64 {
+        self.width * self.height
+    }
+}
+
+impl Display for Crop {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}x{} at {},{}", self.width, self.height, self.x, self.y)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CropMode {
+    Auto,
+    Off,
+    Manual(Crop),
+}
+
+impl CropMode {
+    pub fn parse(mode: &str) -> Option<Self> {
+        match mode.to_lowercase().as_str() {
+            "auto" => Some(CropMode::Auto),
+            "off" => Some(CropMode::Off),
+            crop => Crop::parse(crop).map(CropMode::Manual),
+        }
+    }
+}
+
+/// Run `cropdetect` over short clips spread across the input and settle on
+/// a crop that is safe for all of them.
+pub fn detect_crop(input: &Path, probe: &AVProbeMetadata) -> Result<Option<Crop>, String> {
+    let Some(duration) = probe.duration() else {
+        return Err(String::from("unable to determine duration for crop detection"));
+    };
+    let mut crops = Vec::new();
+    for start in sample_points(duration, SAMPLE_COUNT, SAMPLE_LENGTH) {
+        let output = Command::new("ffmpeg")
+            .args([
+                PathBuf::from("-hide_banner"),
+                PathBuf::from("-nostats"),
+                PathBuf::from("-ss"), PathBuf::from(format!("{:.3}", start)),
+                PathBuf::from("-i"), input.to_path_buf(),
+                PathBuf::from("-t"), PathBuf::from(format!("{:.3}", SAMPLE_LENGTH)),
+                PathBuf::from("-map"), PathBuf::from("0:v:0"),
+                PathBuf::from("-vf"), PathBuf::from("cropdetect=limit=0.094:round=2:reset=0"),
+                PathBuf::from("-f"), PathBuf::from("null"),
+                PathBuf::from("-"),
+            ])
+            .output()
+            .map_err(|err| format!("unable to run ffmpeg: {}", err))?;
+        if let Some(crop) = parse_cropdetect(&String::from_utf8_lossy(&output.stderr)) {
+            crops.push(crop);
+        }
+    }
+    Ok(stable_crop(&crops, probe.width, probe.height))
+}
+
+/// The last rectangle `cropdetect` reported; with `reset=0` it covers
+/// everything seen in the clip.
+fn parse_cropdetect(stderr: &str) -> Option<Crop> {
+    stderr.lines()
+        .rev()
+        .filter_map(|line| line.rsplit_once("crop=").map(|(_, crop)| crop))
+        .find_map(|crop| Crop::parse(crop.split_whitespace().next().unwrap_or_default()))
+}
+
+/// Clips that are mostly black (fades, night scenes) report tiny rectangles
+/// and are ignored. The rest are combined into the smallest rectangle that
+/// contains them all, so a film that switches aspect ratio keeps its widest
+/// shots intact. Crops that would only trim a few lines aren't worth it.
+fn stable_crop(crops: &[Crop], width: u64, height: u64) -> Option<Crop> {
+    let plausible: Vec<&Crop> = crops.iter()
+        .filter(|crop| crop.area() * 4 >= width * height)
+        .filter(|crop| crop.x + crop.width <= width && crop.y + crop.height <= height)
+        .collect();
+    let left = plausible.iter().map(|crop| crop.x).min()?;
+    let top = plausible.iter().map(|crop| crop.y).min()?;
+    let right = plausible.iter().map(|crop| crop.x + crop.width).max()?;
+    let bottom = plausible.iter().map(|crop| crop.y + crop.height).max()?;
+    let crop = Crop {
+        width: (right - left) & !1,
+        height: (bottom - top) & !1,
+        x: left,
+        y: top,
+    };
+    match width - crop.width >= 8 || height - crop.height >= 8 {
+        true => Some(crop),
+        false => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cropdetect() {
+        let stderr = "[Parsed_cropdetect_0 @ 0x55] x1:0 x2:1919 y1:142 y2:937 w:1920 h:796 x:0 y:142 pts:1 t:0.04 limit:0.094 crop=1920:796:0:142\n\
+                      [Parsed_cropdetect_0 @ 0x55] x1:0 x2:1919 y1:140 y2:939 w:1920 h:800 x:0 y:140 pts:2 t:0.08 limit:0.094 crop=1920:800:0:140\n\
+                      [out#0/null @ 0x56] video:1kB audio:0kB\n";
+        assert_eq!(parse_cropdetect(stderr), Some(Crop { width: 1920, height: 800, x: 0, y: 140 }));
+        assert_eq!(parse_cropdetect("nothing here"), None);
+        assert_eq!(CropMode::parse("1920:800:0:140"), Some(CropMode::Manual(Crop { width: 1920, height: 800, x: 0, y: 140 })));
+        assert_eq!(CropMode::parse("1920:800"), None);
+    }
+
+    #[test]
+    fn test_stable_crop() {
+        let scope = Crop { width: 1920, height: 800, x: 0, y: 140 };
+        let imax = Crop { width: 1920, height: 1040, x: 0, y: 20 };
+        let dark = Crop { width: 320, height: 200, x: 800, y: 440 };
+        assert_eq!(stable_crop(&[scope, scope, dark], 1920, 1080), Some(scope));
+        assert_eq!(stable_crop(&[scope, imax, scope], 1920, 1080), Some(imax));
+        assert_eq!(stable_crop(&[Crop { width: 1920, height: 1076, x: 0, y: 2 }], 1920, 1080), None);
+        assert_eq!(stable_crop(&[dark], 1920, 1080), None);
+        assert_eq!(stable_crop(&[], 1920, 1080), None);
+    }
+}
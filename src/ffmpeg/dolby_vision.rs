@@ -0,0 +1,92 @@
+use std::process::Command;
+use std::sync::OnceLock;
+use crate::ffmpeg::probe::DolbyVision;
+
+/// What to do with sources that carry Dolby Vision.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DolbyVisionPolicy {
+    /// Leave them alone.
+    Skip,
+    /// Encode the HDR10/HLG/SDR base layer and drop the RPU.
+    BaseLayer,
+    /// Keep the RPU where the encoder can, otherwise skip.
+    Keep,
+}
+
+impl DolbyVisionPolicy {
+    pub fn parse(policy: &str) -> Option<Self> {
+        match policy.to_lowercase().as_str() {
+            "skip" => Some(DolbyVisionPolicy::Skip),
+            "base-layer" => Some(DolbyVisionPolicy::BaseLayer),
+            "keep" => Some(DolbyVisionPolicy::Keep),
+            _ => None,
+        }
+    }
+}
+
+/// The decision for one file.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum DolbyVisionHandling {
+    /// No Dolby Vision; encode as usual.
+    #[default]
+    None,
+    Skip(String),
+    BaseLayer,
+    /// Pass the RPU through to libx265.
+    KeepRpu,
+}
+
+/// Profile 5 has no backwards compatible base layer, so without the RPU the
+/// picture's colours are simply wrong and it is always skipped. Keeping the
+/// RPU only works for single layer profile 8 into HEVC.
+pub fn decide(dolby_vision: &DolbyVision, policy: DolbyVisionPolicy, codec: &str, rpu_supported: impl Fn() -> bool) -> DolbyVisionHandling {
+    if dolby_vision.profile == 5 {
+        return DolbyVisionHandling::Skip(String::from("Dolby Vision profile 5 has no compatible base layer"));
+    }
+    match policy {
+        DolbyVisionPolicy::Skip => DolbyVisionHandling::Skip(format!("it has Dolby Vision (profile {})", dolby_vision.profile)),
+        DolbyVisionPolicy::BaseLayer => DolbyVisionHandling::BaseLayer,
+        DolbyVisionPolicy::Keep if dolby_vision.profile != 8 || codec != "hevc" =>
+            DolbyVisionHandling::Skip(format!("Dolby Vision profile {} can't be kept when encoding {}", dolby_vision.profile, codec)),
+        DolbyVisionPolicy::Keep if !rpu_supported() =>
+            DolbyVisionHandling::Skip(String::from("this ffmpeg's libx265 can't keep Dolby Vision RPUs")),
+        DolbyVisionPolicy::Keep => DolbyVisionHandling::KeepRpu,
+    }
+}
+
+/// Whether this ffmpeg's libx265 wrapper has the `dolbyvision` option.
+/// Asked once per run, as every Dolby Vision file needs to know.
+pub fn libx265_supports_dolby_vision() -> bool {
+    static SUPPORTED: OnceLock<bool> = OnceLock::new();
+    *SUPPORTED.get_or_init(|| match Command::new("ffmpeg").args(["-hide_banner", "-h", "encoder=libx265"]).output() {
+        Ok(output) => String::from_utf8_lossy(&output.stdout).contains("-dolbyvision"),
+        Err(_) => false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(profile: u8) -> DolbyVision {
+        DolbyVision {
+            profile,
+            level: 6,
+            rpu_present: true,
+            el_present: profile == 7,
+            bl_present: true,
+            bl_compatibility_id: if profile == 5 { 0 } else { 1 },
+        }
+    }
+
+    #[test]
+    fn test_decide() {
+        assert!(matches!(decide(&profile(5), DolbyVisionPolicy::BaseLayer, "av1", || true), DolbyVisionHandling::Skip(_)));
+        assert!(matches!(decide(&profile(8), DolbyVisionPolicy::Skip, "hevc", || true), DolbyVisionHandling::Skip(_)));
+        assert_eq!(decide(&profile(7), DolbyVisionPolicy::BaseLayer, "av1", || true), DolbyVisionHandling::BaseLayer);
+        assert_eq!(decide(&profile(8), DolbyVisionPolicy::Keep, "hevc", || true), DolbyVisionHandling::KeepRpu);
+        assert!(matches!(decide(&profile(8), DolbyVisionPolicy::Keep, "hevc", || false), DolbyVisionHandling::Skip(_)));
+        assert!(matches!(decide(&profile(8), DolbyVisionPolicy::Keep, "av1", || true), DolbyVisionHandling::Skip(_)));
+        assert!(matches!(decide(&profile(7), DolbyVisionPolicy::Keep, "hevc", || true), DolbyVisionHandling::Skip(_)));
+    }
+}
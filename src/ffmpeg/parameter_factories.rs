@@ -1,9 +1,193 @@
 pub mod av1;
+pub mod crf_table;
+pub mod filters;
 pub mod hevc;
+pub mod quality_preset;
 
-use std::path::PathBuf;
-use crate::ffmpeg::probe::AVProbeMetadata;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use crate::ffmpeg::probe::{AVProbeMetadata, ChromaSubsampling};
+use filters::VideoFilters;
 
 pub trait ParameterFactory {
-    fn parameters(&self, input: &PathBuf, probe: &AVProbeMetadata) -> Vec<PathBuf>;
+    fn parameters(&self, input: &Path, probe: &AVProbeMetadata) -> Vec<PathBuf>;
+
+    fn crf(&self) -> u16;
+
+    /// The highest CRF the encoder accepts.
+    fn max_crf(&self) -> u16;
+
+    /// The same parameters at a different CRF.
+    fn with_crf(&self, crf: u16) -> Box<dyn ParameterFactory>;
+
+    /// The filter chain applied ahead of the encoder.
+    fn filters(&self) -> &VideoFilters;
+}
+
+/// Encoder settings given explicitly on the command line; they win over the
+/// quality preset and the factories' own choices.
+#[derive(Clone, Debug, Default)]
+pub struct EncoderOverrides {
+    /// Used as is, without the resolution table or any offsets.
+    pub crf: Option<u16>,
+    /// `key=value` pairs for `-svtav1-params` or `-x265-params`.
+    pub encoder_params: Vec<(String, String)>,
+    /// Extra ffmpeg output arguments.
+    pub ffmpeg_args: Vec<String>,
+}
+
+/// Parse `key=value:key=value` as taken by `-svtav1-params` and
+/// `-x265-params`.
+pub fn parse_encoder_params(params: &str) -> Option<Vec<(String, String)>> {
+    params.split(':')
+        .map(|param| match param.split_once('=') {
+            Some((key, value)) if !key.is_empty() => Some((String::from(key), String::from(value))),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Replace the defaults' values for keys the user gave and append the rest.
+pub fn merge_encoder_params(mut params: Vec<String>, overrides: &[(String, String)]) -> Vec<String> {
+    for (key, value) in overrides {
+        let param = format!("{}={}", key, value);
+        match params.iter_mut().find(|default| default.split_once('=').is_some_and(|(k, _)| k == key)) {
+            Some(default) => *default = param,
+            None => params.push(param),
+        }
+    }
+    params
+}
+
+/// Drop our value for every option the user gives and append theirs, so
+/// `-g 48` changes the GOP rather than giving ffmpeg two, and `-map`s
+/// replace our `-map 0` rather than adding to it. Apply it to the whole
+/// output argument list, as ffmpeg takes the last value of an option.
+pub fn merge_arguments(parameters: Vec<PathBuf>, extra: &[String]) -> Vec<PathBuf> {
+    let overridden: Vec<&OsStr> = split_options(extra).iter().map(|option| option[0].as_ref()).collect();
+    let mut merged: Vec<PathBuf> = split_options(&parameters).into_iter()
+        .filter(|option| !overridden.contains(&option[0].as_os_str()))
+        .flatten()
+        .cloned()
+        .collect();
+    merged.extend(extra.iter().map(PathBuf::from));
+    merged
+}
+
+/// Group arguments into options and their values; anything after an option
+/// that isn't another option (negative numbers aren't) is its value.
+fn split_options<T: AsRef<OsStr>>(args: &[T]) -> Vec<&[T]> {
+    let is_value = |arg: &T| {
+        let arg = arg.as_ref().to_string_lossy();
+        !arg.starts_with('-') || arg.parse::<f64>().is_ok()
+    };
+    let mut options = Vec::new();
+    let mut start = 0;
+    while start < args.len() {
+        let end = match args.get(start + 1) {
+            Some(value) if is_value(value) => start + 2,
+            _ => start + 1,
+        };
+        options.push(&args[start..end]);
+        start = end;
+    }
+    options
+}
+
+/// Tag the output with the source's colour description so players (and
+/// HDR-aware servers) interpret it the same way, or as BT.709 once it has
+/// been tonemapped.
+pub fn color_parameters(probe: &AVProbeMetadata, tonemapped: bool) -> Vec<PathBuf> {
+    if tonemapped {
+        return ["-color_primaries", "bt709", "-color_trc", "bt709", "-colorspace", "bt709", "-color_range", "tv"]
+            .iter().map(PathBuf::from).collect();
+    }
+    let Some(video) = probe.video() else {
+        return Vec::new();
+    };
+    let mut parameters = Vec::new();
+    for (option, value) in [
+        ("-color_primaries", &video.color_primaries),
+        ("-color_trc", &video.color_transfer),
+        ("-colorspace", &video.color_space),
+        ("-color_range", &video.color_range),
+    ] {
+        if let Some(value) = value
+            && !matches!(value.as_str(), "unknown" | "unspecified" | "reserved") {
+            parameters.extend([PathBuf::from(option), PathBuf::from(value)]);
+        }
+    }
+    parameters
+}
+
+/// The ffmpeg pix_fmt for planar YUV at `bit_depth`, e.g. `yuv420p10le`.
+/// Subsampling we can't reproduce falls back to 4:2:0.
+pub fn pix_fmt(chroma_subsampling: ChromaSubsampling, bit_depth: u8) -> String {
+    let layout = match chroma_subsampling {
+        ChromaSubsampling::Yuv422 => "422",
+        ChromaSubsampling::Yuv444 => "444",
+        _ => "420",
+    };
+    match bit_depth {
+        0..=8 => format!("yuv{}p", layout),
+        depth => format!("yuv{}p{}le", layout, depth),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_color_parameters() {
+        use crate::ffmpeg::probe::{AVStream, AVStreamKind, AVVideoStream};
+        let mut probe = AVProbeMetadata::empty();
+        assert!(color_parameters(&probe, false).is_empty());
+        probe.streams.push(AVStream::for_test(0, "hevc", AVStreamKind::Video(Box::new(AVVideoStream {
+            color_primaries: Some(String::from("bt2020")),
+            color_transfer: Some(String::from("smpte2084")),
+            color_range: Some(String::from("unknown")),
+            ..AVVideoStream::default()
+        }))));
+        assert_eq!(color_parameters(&probe, false), vec![
+            PathBuf::from("-color_primaries"), PathBuf::from("bt2020"),
+            PathBuf::from("-color_trc"), PathBuf::from("smpte2084"),
+        ]);
+        assert_eq!(color_parameters(&probe, true)[..2], [PathBuf::from("-color_primaries"), PathBuf::from("bt709")]);
+    }
+
+    #[test]
+    fn test_encoder_params() {
+        let overrides = parse_encoder_params("tune=1:enable-qm=1").unwrap();
+        assert_eq!(overrides, vec![
+            (String::from("tune"), String::from("1")),
+            (String::from("enable-qm"), String::from("1")),
+        ]);
+        assert_eq!(merge_encoder_params(vec![String::from("tune=0"), String::from("enable-tf=0")], &overrides),
+            vec!["tune=1", "enable-tf=0", "enable-qm=1"]);
+        assert_eq!(parse_encoder_params("tune"), None);
+        assert_eq!(parse_encoder_params("=1"), None);
+    }
+
+    #[test]
+    fn test_merge_arguments() {
+        let defaults: Vec<PathBuf> = ["-c:v", "libsvtav1", "-preset", "4", "-g", "240", "-c:a", "copy", "-map", "0"]
+            .iter().map(PathBuf::from).collect();
+        let extra: Vec<String> = ["-g", "48", "-preset", "-1", "-an"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(merge_arguments(defaults.clone(), &extra), ["-c:v", "libsvtav1", "-c:a", "copy", "-map", "0", "-g", "48", "-preset", "-1", "-an"]
+            .iter().map(PathBuf::from).collect::<Vec<_>>());
+
+        let extra: Vec<String> = ["-c:a", "aac", "-b:a", "192k", "-map", "0:v", "-map", "0:a:0"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(merge_arguments(defaults, &extra), ["-c:v", "libsvtav1", "-preset", "4", "-g", "240",
+            "-c:a", "aac", "-b:a", "192k", "-map", "0:v", "-map", "0:a:0"]
+            .iter().map(PathBuf::from).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_pix_fmt() {
+        assert_eq!(pix_fmt(ChromaSubsampling::Yuv420, 8), "yuv420p");
+        assert_eq!(pix_fmt(ChromaSubsampling::Yuv420, 10), "yuv420p10le");
+        assert_eq!(pix_fmt(ChromaSubsampling::Yuv444, 12), "yuv444p12le");
+        assert_eq!(pix_fmt(ChromaSubsampling::Mono, 8), "yuv420p");
+    }
 }
@@ -1,42 +1,158 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use crate::ffmpeg::analysis::SourceAnalysis;
 use crate::ffmpeg::compressor::CompressorOptions;
-use crate::ffmpeg::probe::AVProbeMetadata;
-use super::ParameterFactory;
+use crate::ffmpeg::content::ContentType;
+use crate::ffmpeg::probe::{AVProbeMetadata, ChromaSubsampling, HdrFormat, MasteringDisplay};
+use super::crf_table::{select_crf, CrfDefaults};
+use super::filters::VideoFilters;
+use super::{color_parameters, merge_encoder_params, pix_fmt, EncoderOverrides, ParameterFactory};
 
+#[derive(Clone)]
 pub struct Av1ParameterFactory {
     crf: u16,
-    preset: u16,
+    preset: i8,
+    tune: u8,
+    filters: VideoFilters,
+    overrides: EncoderOverrides,
+    film_grain: Option<u8>,
+    film_grain_denoise: bool,
+    animation: bool,
 }
 
+/// The lowest CRF libsvtav1 accepts; it takes 0 as unset.
+pub const MIN_CRF: u16 = 1;
+/// The highest CRF libsvtav1 accepts.
+pub const MAX_CRF: u16 = 63;
+
+const CRF_DEFAULTS: CrfDefaults = CrfDefaults {
+    table: "480:20,720:23,1080:25,2160:29",
+    animation_offset: 3,
+    max_crf: MAX_CRF,
+};
+
 impl Av1ParameterFactory {
-    pub fn new(options: &CompressorOptions) -> Self {
+    pub fn new(options: &CompressorOptions, analysis: &SourceAnalysis, probe: &AVProbeMetadata) -> Self {
+        let filters = VideoFilters::new(options, analysis);
+        let animation = analysis.content == ContentType::Animation;
+        let settings = &options.quality.av1;
         Av1ParameterFactory {
-            crf: if options.fast {
-                if options.extreme {
-                    25
-                } else {
-                    35
-                }
-            } else {
-                if options.extreme {
-                    18
-                } else {
-                    25
-                }
-            },
-            preset: if options.fast { 12 } else { 2 },
+            crf: select_crf(options, analysis, &filters, probe, &CRF_DEFAULTS, settings.crf_offset),
+            preset: settings.preset,
+            tune: settings.tune,
+            filters,
+            overrides: options.overrides.clone(),
+            film_grain: analysis.film_grain,
+            film_grain_denoise: options.film_grain_denoise,
+            animation,
         }
     }
 }
 
 impl ParameterFactory for Av1ParameterFactory {
-    fn parameters(&self, _input: &PathBuf, probe: &AVProbeMetadata) -> Vec<PathBuf> {
-        vec![
+    fn parameters(&self, _input: &Path, probe: &AVProbeMetadata) -> Vec<PathBuf> {
+        let gop = probe.frame_rate as usize * self.filters.frame_rate_factor(probe);
+        let mut parameters = self.filters.parameters(probe);
+        parameters.extend([
             PathBuf::from("-c:v"), PathBuf::from("libsvtav1"),
+            // 10-bit even for 8-bit sources: it costs nothing in size and avoids banding
+            PathBuf::from("-pix_fmt"), PathBuf::from(pix_fmt(ChromaSubsampling::Yuv420, 10)),
             PathBuf::from("-crf"), PathBuf::from(self.crf.to_string()),
             PathBuf::from("-preset"), PathBuf::from(self.preset.to_string()),
-            PathBuf::from("-svtav1-params"), PathBuf::from("tune=0"),
-            PathBuf::from("-g"), PathBuf::from(probe.frame_rate.to_string()),
-        ]
+            PathBuf::from("-svtav1-params"), PathBuf::from(merge_encoder_params(svtav1_params(
+                probe, self.filters.output_hdr_format(probe), self.tune, self.film_grain, self.film_grain_denoise, self.animation),
+                &self.overrides.encoder_params).join(":")),
+            PathBuf::from("-g"), PathBuf::from(gop.to_string()),
+        ]);
+        parameters.extend(color_parameters(probe, self.filters.tonemaps(probe)));
+        parameters
+    }
+
+    fn crf(&self) -> u16 {
+        self.crf
+    }
+
+    fn max_crf(&self) -> u16 {
+        MAX_CRF
+    }
+
+    fn with_crf(&self, crf: u16) -> Box<dyn ParameterFactory> {
+        Box::new(Av1ParameterFactory {
+            crf: crf.min(self.max_crf()),
+            ..self.clone()
+        })
+    }
+
+    fn filters(&self) -> &VideoFilters {
+        &self.filters
+    }
+}
+
+/// Grain is synthesised by the decoder rather than encoded; denoising first
+/// lets the encoder spend nothing on the original grain, at the cost of
+/// some fine detail. Temporal filtering smears line art, so animation goes
+/// without.
+fn svtav1_params(probe: &AVProbeMetadata, hdr: Option<HdrFormat>, tune: u8, film_grain: Option<u8>, film_grain_denoise: bool, animation: bool) -> Vec<String> {
+    let mut params = vec![format!("tune={}", tune)];
+    if animation {
+        params.push(String::from("enable-tf=0"));
+    }
+    if let Some(level) = film_grain && level > 0 {
+        params.push(format!("film-grain={}", level));
+        params.push(format!("film-grain-denoise={}", film_grain_denoise as u8));
+    }
+    if hdr.is_some() && let Some(video) = probe.video() {
+        params.push(String::from("enable-hdr=1"));
+        if let Some(display) = &video.mastering_display {
+            params.push(format!("mastering-display={}", mastering_display(display)));
+        }
+        if let Some(light) = &video.content_light_level {
+            params.push(format!("content-light={},{}", light.max_content, light.max_average));
+        }
+    }
+    params
+}
+
+/// SVT-AV1 takes chromaticities and luminance as plain decimals.
+fn mastering_display(display: &MasteringDisplay) -> String {
+    format!("G({:.4},{:.4})B({:.4},{:.4})R({:.4},{:.4})WP({:.4},{:.4})L({},{})",
+        display.green.0, display.green.1,
+        display.blue.0, display.blue.1,
+        display.red.0, display.red.1,
+        display.white_point.0, display.white_point.1,
+        display.max_luminance, display.min_luminance)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffmpeg::parameter_factories::crf_table::CrfTable;
+
+    #[test]
+    fn test_default_crf_table() {
+        let table = CrfTable::parse(CRF_DEFAULTS.table).unwrap();
+        assert_eq!(table.lookup(1920, 1080, 24.0), 25);
+    }
+
+    #[test]
+    fn test_svtav1_params() {
+        let probe = AVProbeMetadata::empty();
+        assert_eq!(svtav1_params(&probe, None, 0, None, true, false), vec!["tune=0"]);
+        assert_eq!(svtav1_params(&probe, None, 1, Some(0), true, false), vec!["tune=1"]);
+        assert_eq!(svtav1_params(&probe, None, 0, Some(8), false, false), vec!["tune=0", "film-grain=8", "film-grain-denoise=0"]);
+        assert_eq!(svtav1_params(&probe, None, 0, None, false, true), vec!["tune=0", "enable-tf=0"]);
+    }
+
+    #[test]
+    fn test_mastering_display() {
+        let display = MasteringDisplay {
+            red: (0.68, 0.32),
+            green: (0.265, 0.69),
+            blue: (0.15, 0.06),
+            white_point: (0.3127, 0.329),
+            min_luminance: 0.005,
+            max_luminance: 1000.0,
+        };
+        assert_eq!(mastering_display(&display),
+            "G(0.2650,0.6900)B(0.1500,0.0600)R(0.6800,0.3200)WP(0.3127,0.3290)L(1000,0.005)");
     }
 }
@@ -0,0 +1,129 @@
+use crate::ffmpeg::analysis::SourceAnalysis;
+use crate::ffmpeg::compressor::CompressorOptions;
+use crate::ffmpeg::content::ContentType;
+use crate::ffmpeg::probe::AVProbeMetadata;
+use super::filters::VideoFilters;
+
+/// One row of a CRF table: outputs up to `lines` (and at least `fps`, when
+/// given) use `crf`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct CrfTableEntry {
+    lines: u64,
+    fps: Option<f64>,
+    crf: u16,
+}
+
+/// Base CRFs keyed by output resolution, written `lines[@fps]:crf,...`, e.g.
+/// `480:20,720:23,1080:25,1080@50:27,2160:30`. An output uses the row for
+/// the smallest resolution that holds it, preferring the row with the
+/// highest frame rate it reaches.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CrfTable {
+    entries: Vec<CrfTableEntry>,
+}
+
+impl CrfTable {
+    pub fn parse(table: &str) -> Option<Self> {
+        let mut entries = Vec::new();
+        for row in table.split(',') {
+            let (key, crf) = row.trim().split_once(':')?;
+            let (lines, fps) = match key.split_once('@') {
+                Some((lines, fps)) => (lines, Some(fps.parse().ok()?)),
+                None => (key, None),
+            };
+            entries.push(CrfTableEntry {
+                lines: lines.trim_end_matches('p').parse().ok()?,
+                fps,
+                crf: crf.parse().ok()?,
+            });
+        }
+        entries.sort_by(|a, b| a.lines.cmp(&b.lines).then(a.fps.unwrap_or(0.0).total_cmp(&b.fps.unwrap_or(0.0))));
+        match entries.is_empty() {
+            true => None,
+            false => Some(CrfTable { entries }),
+        }
+    }
+
+    pub fn lookup(&self, width: u64, height: u64, fps: f64) -> u16 {
+        let lines = effective_lines(width, height);
+        let row_lines = self.entries.iter()
+            .map(|entry| entry.lines)
+            .find(|row_lines| *row_lines >= lines)
+            .unwrap_or(self.entries[self.entries.len() - 1].lines);
+        self.entries.iter()
+            .rev()
+            .find(|entry| entry.lines == row_lines && entry.fps.is_none_or(|min| fps >= min))
+            .or(self.entries.iter().find(|entry| entry.lines == row_lines))
+            .map(|entry| entry.crf)
+            .unwrap_or_default()
+    }
+}
+
+/// Lines a picture would have at 16:9, so a 1920x800 scope film counts as
+/// 1080p rather than 800p.
+fn effective_lines(width: u64, height: u64) -> u64 {
+    height.max(width * 9 / 16)
+}
+
+/// A codec's CRF starting points.
+pub struct CrfDefaults {
+    /// Base CRFs by output resolution, used without --crf-table.
+    pub table: &'static str,
+    /// Added for animation, whose flat fills survive a higher CRF untouched.
+    pub animation_offset: i32,
+    pub max_crf: u16,
+}
+
+/// The CRF to encode `probe` at: --crf as given, otherwise the table's CRF
+/// for the encoded size and frame rate, moved by the quality preset's
+/// `preset_offset` and the codec's animation offset.
+pub fn select_crf(options: &CompressorOptions, analysis: &SourceAnalysis, filters: &VideoFilters, probe: &AVProbeMetadata, defaults: &CrfDefaults, preset_offset: i32) -> u16 {
+    if let Some(crf) = options.overrides.crf {
+        return crf;
+    }
+    let table = options.crf_table.clone()
+        .or(CrfTable::parse(defaults.table))
+        .expect("default CRF table parses");
+    let (width, height) = filters.encoded_size(probe);
+    let fps = probe.exact_frame_rate() * filters.frame_rate_factor(probe) as f64;
+    let animation_offset = match analysis.content {
+        ContentType::Animation => defaults.animation_offset,
+        ContentType::LiveAction => 0,
+    };
+    offset_crf(table.lookup(width, height, fps), preset_offset + animation_offset, defaults.max_crf)
+}
+
+/// Add a signed offset to a CRF, keeping it within `1..=max_crf`.
+fn offset_crf(crf: u16, offset: i32, max_crf: u16) -> u16 {
+    (crf as i32 + offset).clamp(1, max_crf as i32) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crf_table() {
+        let table = CrfTable::parse("480:20, 720:23, 1080:25, 1080@50:27, 2160p:30").unwrap();
+        assert_eq!(table.lookup(720, 480, 29.97), 20);
+        assert_eq!(table.lookup(720, 576, 25.0), 23);
+        assert_eq!(table.lookup(1920, 800, 23.976), 25);
+        assert_eq!(table.lookup(1920, 1080, 59.94), 27);
+        assert_eq!(table.lookup(3840, 2160, 24.0), 30);
+        assert_eq!(table.lookup(7680, 4320, 24.0), 30);
+
+        let high_fps_only = CrfTable::parse("1080@50:27").unwrap();
+        assert_eq!(high_fps_only.lookup(1920, 1080, 24.0), 27);
+
+        assert_eq!(CrfTable::parse(""), None);
+        assert_eq!(CrfTable::parse("1080"), None);
+        assert_eq!(CrfTable::parse("1080:abc"), None);
+    }
+
+    #[test]
+    fn test_offset_crf() {
+        assert_eq!(offset_crf(25, 10, 63), 35);
+        assert_eq!(offset_crf(25, -30, 63), 1);
+        assert_eq!(offset_crf(50, 5, 51), 51);
+    }
+}
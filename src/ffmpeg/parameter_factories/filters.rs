@@ -0,0 +1,410 @@
+use std::path::PathBuf;
+use crate::ffmpeg::analysis::SourceAnalysis;
+use crate::ffmpeg::compressor::CompressorOptions;
+use crate::ffmpeg::cropdetect::Crop;
+use crate::ffmpeg::probe::{AVProbeMetadata, HdrFormat};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeinterlaceMode {
+    /// Deinterlace sources whose field order says they're interlaced.
+    Auto,
+    On,
+    Off,
+}
+
+impl DeinterlaceMode {
+    pub fn parse(mode: &str) -> Option<Self> {
+        match mode.to_lowercase().as_str() {
+            "auto" => Some(DeinterlaceMode::Auto),
+            "on" => Some(DeinterlaceMode::On),
+            "off" => Some(DeinterlaceMode::Off),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Deinterlacer {
+    Bwdif,
+    Yadif,
+}
+
+impl Deinterlacer {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "bwdif" => Some(Deinterlacer::Bwdif),
+            "yadif" => Some(Deinterlacer::Yadif),
+            _ => None,
+        }
+    }
+}
+
+/// Whether each frame (two fields) or each field becomes an output frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeinterlaceRate {
+    Frame,
+    Field,
+}
+
+impl DeinterlaceRate {
+    pub fn parse(rate: &str) -> Option<Self> {
+        match rate.to_lowercase().as_str() {
+            "frame" => Some(DeinterlaceRate::Frame),
+            "field" => Some(DeinterlaceRate::Field),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Deinterlace {
+    pub mode: DeinterlaceMode,
+    pub deinterlacer: Deinterlacer,
+    pub rate: DeinterlaceRate,
+}
+
+impl Deinterlace {
+    pub fn applies(&self, probe: &AVProbeMetadata) -> bool {
+        match self.mode {
+            DeinterlaceMode::Auto => probe.interlaced,
+            DeinterlaceMode::On => true,
+            DeinterlaceMode::Off => false,
+        }
+    }
+
+    fn filter(&self) -> String {
+        format!("{}=mode={}:parity=auto:deint=all",
+            match self.deinterlacer {
+                Deinterlacer::Bwdif => "bwdif",
+                Deinterlacer::Yadif => "yadif",
+            },
+            match self.rate {
+                DeinterlaceRate::Frame => "send_frame",
+                DeinterlaceRate::Field => "send_field",
+            })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TonemapAlgorithm {
+    Clip,
+    Linear,
+    Gamma,
+    Reinhard,
+    Hable,
+    Mobius,
+}
+
+impl TonemapAlgorithm {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "clip" => Some(TonemapAlgorithm::Clip),
+            "linear" => Some(TonemapAlgorithm::Linear),
+            "gamma" => Some(TonemapAlgorithm::Gamma),
+            "reinhard" => Some(TonemapAlgorithm::Reinhard),
+            "hable" => Some(TonemapAlgorithm::Hable),
+            "mobius" => Some(TonemapAlgorithm::Mobius),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            TonemapAlgorithm::Clip => "clip",
+            TonemapAlgorithm::Linear => "linear",
+            TonemapAlgorithm::Gamma => "gamma",
+            TonemapAlgorithm::Reinhard => "reinhard",
+            TonemapAlgorithm::Hable => "hable",
+            TonemapAlgorithm::Mobius => "mobius",
+        }
+    }
+}
+
+/// Map HDR down to BT.709 SDR.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tonemap {
+    pub algorithm: TonemapAlgorithm,
+    /// Nominal peak luminance of the SDR target, in cd/m².
+    pub peak: f64,
+}
+
+impl Tonemap {
+    /// Linearise, convert primaries in linear light, tonemap, then apply the
+    /// BT.709 transfer and matrix.
+    fn filter(&self) -> String {
+        format!("zscale=t=linear:npl={},format=gbrpf32le,zscale=p=bt709,tonemap=tonemap={}:desat=0,zscale=t=bt709:m=bt709:r=tv",
+            self.peak, self.algorithm.name())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scaler {
+    Bilinear,
+    Bicubic,
+    Lanczos,
+    Spline,
+    Area,
+}
+
+impl Scaler {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "bilinear" => Some(Scaler::Bilinear),
+            "bicubic" => Some(Scaler::Bicubic),
+            "lanczos" => Some(Scaler::Lanczos),
+            "spline" => Some(Scaler::Spline),
+            "area" => Some(Scaler::Area),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Scaler::Bilinear => "bilinear",
+            Scaler::Bicubic => "bicubic",
+            Scaler::Lanczos => "lanczos",
+            Scaler::Spline => "spline",
+            Scaler::Area => "area",
+        }
+    }
+}
+
+/// Shrink anything larger than these bounds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Scale {
+    pub max_width: Option<u64>,
+    pub max_height: Option<u64>,
+    pub scaler: Scaler,
+}
+
+impl Scale {
+    /// The largest even dimensions within the bounds that keep the aspect
+    /// ratio, or `None` when the picture already fits.
+    pub fn scaled_size(&self, width: u64, height: u64) -> Option<(u64, u64)> {
+        if width == 0 || height == 0 {
+            return None;
+        }
+        let ratio = [
+            self.max_width.map(|max| max as f64 / width as f64),
+            self.max_height.map(|max| max as f64 / height as f64),
+        ].into_iter().flatten().fold(1.0, f64::min);
+        if ratio >= 1.0 {
+            return None;
+        }
+        let even = |size: u64, max: Option<u64>| {
+            let size = ((size as f64 * ratio / 2.0).round() as u64 * 2).max(2);
+            match max {
+                Some(max) if size > max => (max & !1).max(2),
+                _ => size,
+            }
+        };
+        Some((even(width, self.max_width), even(height, self.max_height)))
+    }
+}
+
+/// The video filter chain every encoder applies before encoding.
+#[derive(Clone, Debug)]
+pub struct VideoFilters {
+    deinterlace: Deinterlace,
+    crop: Option<Crop>,
+    scale: Scale,
+    tonemap: Option<Tonemap>,
+}
+
+impl VideoFilters {
+    pub fn new(options: &CompressorOptions, analysis: &SourceAnalysis) -> Self {
+        VideoFilters {
+            deinterlace: options.deinterlace,
+            crop: analysis.crop,
+            scale: options.scale,
+            tonemap: options.tonemap,
+        }
+    }
+
+    pub fn chain(&self, probe: &AVProbeMetadata) -> Vec<String> {
+        self.filters(probe, true)
+    }
+
+    /// Deinterlace before cropping so the crop can't split a field pair, and
+    /// crop and scale before tonemapping so there's less to tonemap.
+    fn filters(&self, probe: &AVProbeMetadata, scale: bool) -> Vec<String> {
+        let mut filters = Vec::new();
+        if self.deinterlace.applies(probe) {
+            filters.push(self.deinterlace.filter());
+        }
+        if let Some(crop) = &self.crop {
+            filters.push(crop.filter());
+        }
+        if scale && let Some((width, height)) = self.output_size(probe) {
+            filters.push(format!("scale={}:{}:flags={}", width, height, self.scale.scaler.name()));
+        }
+        if let Some(tonemap) = &self.tonemap && probe.hdr_format().is_some() {
+            filters.push(tonemap.filter());
+        }
+        filters
+    }
+
+    /// Whether this source is tonemapped to SDR.
+    pub fn tonemaps(&self, probe: &AVProbeMetadata) -> bool {
+        self.tonemap.is_some() && probe.hdr_format().is_some()
+    }
+
+    /// The HDR format the output keeps, if any.
+    pub fn output_hdr_format(&self, probe: &AVProbeMetadata) -> Option<HdrFormat> {
+        match self.tonemaps(probe) {
+            true => None,
+            false => probe.hdr_format(),
+        }
+    }
+
+    /// The dimensions of the reference once `reference_chain` is applied.
+    pub fn reference_size(&self, probe: &AVProbeMetadata) -> (u64, u64) {
+        match &self.crop {
+            Some(crop) => (crop.width, crop.height),
+            None => (probe.width, probe.height),
+        }
+    }
+
+    /// The size of the encoded picture after cropping and scaling.
+    pub fn encoded_size(&self, probe: &AVProbeMetadata) -> (u64, u64) {
+        self.output_size(probe).unwrap_or(self.reference_size(probe))
+    }
+
+    /// The downscaled size, when the cropped source exceeds the bounds.
+    pub fn output_size(&self, probe: &AVProbeMetadata) -> Option<(u64, u64)> {
+        let (width, height) = self.reference_size(probe);
+        self.scale.scaled_size(width, height)
+    }
+
+    /// The filters a quality metric's reference needs so it lines up frame
+    /// for frame with the encoded output. Downscaled outputs are scaled back
+    /// up to the reference instead, so scores reflect what was lost.
+    pub fn reference_chain(&self, probe: &AVProbeMetadata) -> Vec<String> {
+        self.filters(probe, false)
+    }
+
+    /// `-vf` and the chain, or nothing when there's nothing to do.
+    pub fn parameters(&self, probe: &AVProbeMetadata) -> Vec<PathBuf> {
+        let filters = self.chain(probe);
+        match filters.is_empty() {
+            true => Vec::new(),
+            false => vec![PathBuf::from("-vf"), PathBuf::from(filters.join(","))],
+        }
+    }
+
+    /// How many output frames each source frame becomes.
+    pub fn frame_rate_factor(&self, probe: &AVProbeMetadata) -> usize {
+        match (self.deinterlace.applies(probe), self.deinterlace.rate) {
+            (true, DeinterlaceRate::Field) => 2,
+            _ => 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffmpeg::probe::{AVStream, AVStreamKind, AVVideoStream};
+
+    #[test]
+    fn test_deinterlace() {
+        let mut probe = AVProbeMetadata::empty();
+        let mut filters = VideoFilters {
+            deinterlace: Deinterlace {
+                mode: DeinterlaceMode::Auto,
+                deinterlacer: Deinterlacer::Bwdif,
+                rate: DeinterlaceRate::Field,
+            },
+            crop: None,
+            scale: Scale { max_width: None, max_height: None, scaler: Scaler::Lanczos },
+            tonemap: None,
+        };
+        assert!(filters.parameters(&probe).is_empty());
+        assert_eq!(filters.frame_rate_factor(&probe), 1);
+
+        probe.interlaced = true;
+        assert_eq!(filters.chain(&probe), vec!["bwdif=mode=send_field:parity=auto:deint=all"]);
+        assert_eq!(filters.frame_rate_factor(&probe), 2);
+
+        filters.deinterlace.mode = DeinterlaceMode::Off;
+        assert!(filters.chain(&probe).is_empty());
+
+        probe.interlaced = false;
+        filters.deinterlace = Deinterlace {
+            mode: DeinterlaceMode::On,
+            deinterlacer: Deinterlacer::Yadif,
+            rate: DeinterlaceRate::Frame,
+        };
+        assert_eq!(filters.chain(&probe), vec!["yadif=mode=send_frame:parity=auto:deint=all"]);
+        assert_eq!(filters.frame_rate_factor(&probe), 1);
+
+        filters.crop = Some(Crop { width: 720, height: 432, x: 0, y: 72 });
+        assert_eq!(filters.parameters(&probe), vec![
+            PathBuf::from("-vf"), PathBuf::from("yadif=mode=send_frame:parity=auto:deint=all,crop=720:432:0:72")]);
+        assert_eq!(filters.reference_size(&probe), (720, 432));
+    }
+
+    #[test]
+    fn test_tonemap() {
+        let mut probe = AVProbeMetadata::empty();
+        let filters = VideoFilters {
+            deinterlace: Deinterlace {
+                mode: DeinterlaceMode::Off,
+                deinterlacer: Deinterlacer::Bwdif,
+                rate: DeinterlaceRate::Frame,
+            },
+            crop: None,
+            scale: Scale { max_width: None, max_height: None, scaler: Scaler::Lanczos },
+            tonemap: Some(Tonemap { algorithm: TonemapAlgorithm::Hable, peak: 100.0 }),
+        };
+        assert!(!filters.tonemaps(&probe));
+        assert!(filters.chain(&probe).is_empty());
+
+        probe.streams.push(hdr_stream());
+        assert!(filters.tonemaps(&probe));
+        assert_eq!(filters.output_hdr_format(&probe), None);
+        assert_eq!(filters.chain(&probe), vec![
+            "zscale=t=linear:npl=100,format=gbrpf32le,zscale=p=bt709,tonemap=tonemap=hable:desat=0,zscale=t=bt709:m=bt709:r=tv"]);
+    }
+
+    #[test]
+    fn test_scaled_size() {
+        let scale = |max_width, max_height| Scale { max_width, max_height, scaler: Scaler::Lanczos };
+        assert_eq!(scale(None, Some(1080)).scaled_size(3840, 2160), Some((1920, 1080)));
+        assert_eq!(scale(None, Some(1080)).scaled_size(1920, 800), None);
+        assert_eq!(scale(Some(1920), None).scaled_size(3840, 1600), Some((1920, 800)));
+        assert_eq!(scale(Some(1280), Some(720)).scaled_size(1920, 816), Some((1280, 544)));
+        assert_eq!(scale(None, Some(480)).scaled_size(1440, 1080), Some((640, 480)));
+        assert_eq!(scale(Some(1000), None).scaled_size(1920, 1080), Some((1000, 562)));
+        assert_eq!(scale(None, None).scaled_size(3840, 2160), None);
+        assert_eq!(scale(None, Some(1080)).scaled_size(1920, 1080), None);
+    }
+
+    #[test]
+    fn test_scale_filter() {
+        let mut probe = AVProbeMetadata::empty();
+        (probe.width, probe.height) = (3840, 2160);
+        let filters = VideoFilters {
+            deinterlace: Deinterlace {
+                mode: DeinterlaceMode::Off,
+                deinterlacer: Deinterlacer::Bwdif,
+                rate: DeinterlaceRate::Frame,
+            },
+            crop: Some(Crop { width: 3840, height: 1600, x: 0, y: 280 }),
+            scale: Scale { max_width: None, max_height: Some(1080), scaler: Scaler::Bicubic },
+            tonemap: None,
+        };
+        assert_eq!(filters.output_size(&probe), Some((2592, 1080)));
+        assert_eq!(filters.encoded_size(&probe), (2592, 1080));
+        assert_eq!(filters.chain(&probe), vec!["crop=3840:1600:0:280", "scale=2592:1080:flags=bicubic"]);
+        assert_eq!(filters.reference_chain(&probe), vec!["crop=3840:1600:0:280"]);
+        assert_eq!(filters.reference_size(&probe), (3840, 1600));
+    }
+
+    fn hdr_stream() -> AVStream {
+        AVStream::for_test(0, "hevc", AVStreamKind::Video(Box::new(AVVideoStream {
+            color_transfer: Some(String::from("smpte2084")),
+            ..AVVideoStream::default()
+        })))
+    }
+}
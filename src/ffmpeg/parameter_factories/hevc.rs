@@ -1,30 +1,165 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use crate::ffmpeg::analysis::SourceAnalysis;
 use crate::ffmpeg::compressor::CompressorOptions;
-use crate::ffmpeg::probe::AVProbeMetadata;
-use super::ParameterFactory;
+use crate::ffmpeg::content::ContentType;
+use crate::ffmpeg::dolby_vision::{libx265_supports_dolby_vision, DolbyVisionHandling};
+use crate::ffmpeg::probe::{AVProbeMetadata, HdrFormat, MasteringDisplay};
+use super::crf_table::{select_crf, CrfDefaults};
+use super::filters::VideoFilters;
+use super::{color_parameters, merge_encoder_params, pix_fmt, EncoderOverrides, ParameterFactory};
 
+#[derive(Clone)]
 pub struct HevcParameterFactory {
     crf: u16,
     preset: String,
+    tune: Option<String>,
+    filters: VideoFilters,
+    overrides: EncoderOverrides,
+    /// The `dolbyvision` option to give libx265, if any.
+    dolby_vision: Option<bool>,
 }
 
+/// The lowest CRF libx265 accepts.
+pub const MIN_CRF: u16 = 0;
+/// The highest CRF libx265 accepts.
+pub const MAX_CRF: u16 = 51;
+
+const CRF_DEFAULTS: CrfDefaults = CrfDefaults {
+    table: "480:18,720:19,1080:20,2160:23",
+    animation_offset: 2,
+    max_crf: MAX_CRF,
+};
+
 impl HevcParameterFactory {
-    pub fn new(options: &CompressorOptions) -> Self {
+    pub fn new(options: &CompressorOptions, analysis: &SourceAnalysis, probe: &AVProbeMetadata) -> Self {
+        let filters = VideoFilters::new(options, analysis);
+        let animation = analysis.content == ContentType::Animation;
+        let settings = &options.quality.hevc;
         HevcParameterFactory {
-            crf: if options.fast { 35 } else { 20 },
-            preset: if options.fast { String::from("veryfast") } else { String::from("slower") },
+            crf: select_crf(options, analysis, &filters, probe, &CRF_DEFAULTS, settings.crf_offset),
+            preset: settings.preset.clone(),
+            // x265 takes a single tune, and animation matters more
+            tune: if animation { Some(String::from("animation")) } else { settings.tune.clone() },
+            filters,
+            overrides: options.overrides.clone(),
+            dolby_vision: match analysis.dolby_vision {
+                DolbyVisionHandling::KeepRpu => Some(true),
+                // libx265's dolbyvision defaults to auto, which would carry the RPU through
+                DolbyVisionHandling::BaseLayer if libx265_supports_dolby_vision() => Some(false),
+                _ => None,
+            },
         }
     }
 }
 
 impl ParameterFactory for HevcParameterFactory {
-    fn parameters(&self, _input: &PathBuf, probe: &AVProbeMetadata) -> Vec<PathBuf> {
-        vec![
+    fn parameters(&self, _input: &Path, probe: &AVProbeMetadata) -> Vec<PathBuf> {
+        let gop = probe.frame_rate as usize * self.filters.frame_rate_factor(probe);
+        let mut parameters = self.filters.parameters(probe);
+        parameters.extend([
             PathBuf::from("-c:v"), PathBuf::from("libx265"),
+            PathBuf::from("-pix_fmt"), PathBuf::from(pix_fmt(probe.chroma_subsampling, output_bit_depth(probe.bit_depth))),
             PathBuf::from("-crf"), PathBuf::from(self.crf.to_string()),
             PathBuf::from("-preset"), PathBuf::from(&self.preset),
-            PathBuf::from("-g"), PathBuf::from(probe.frame_rate.to_string()),
+            PathBuf::from("-g"), PathBuf::from(gop.to_string()),
             PathBuf::from("-tag:v"), PathBuf::from("hvc1"),
-        ]
+        ]);
+        if let Some(tune) = &self.tune {
+            parameters.extend([PathBuf::from("-tune"), PathBuf::from(tune)]);
+        }
+        parameters.extend(color_parameters(probe, self.filters.tonemaps(probe)));
+        // an RPU describes the HDR picture, so it can't survive tonemapping
+        match (self.dolby_vision, self.filters.tonemaps(probe)) {
+            (Some(true), false) => parameters.extend([PathBuf::from("-dolbyvision"), PathBuf::from("1")]),
+            (Some(_), _) => parameters.extend([PathBuf::from("-dolbyvision"), PathBuf::from("0")]),
+            (None, _) => {},
+        }
+        let x265_params = merge_encoder_params(x265_params(probe, self.filters.output_hdr_format(probe)), &self.overrides.encoder_params);
+        if !x265_params.is_empty() {
+            parameters.extend([PathBuf::from("-x265-params"), PathBuf::from(x265_params.join(":"))]);
+        }
+        parameters
+    }
+
+    fn crf(&self) -> u16 {
+        self.crf
+    }
+
+    fn max_crf(&self) -> u16 {
+        MAX_CRF
+    }
+
+    fn with_crf(&self, crf: u16) -> Box<dyn ParameterFactory> {
+        Box::new(HevcParameterFactory {
+            crf: crf.min(self.max_crf()),
+            ..self.clone()
+        })
+    }
+
+    fn filters(&self) -> &VideoFilters {
+        &self.filters
+    }
+}
+
+/// HDR signalling; ffmpeg already passes the colour description to x265,
+/// but not the static metadata, and the headers need repeating for players
+/// that join mid-stream.
+fn x265_params(probe: &AVProbeMetadata, hdr: Option<HdrFormat>) -> Vec<String> {
+    let (Some(format), Some(video)) = (hdr, probe.video()) else {
+        return Vec::new();
+    };
+    let mut params = vec![String::from("repeat-headers=1")];
+    if format == HdrFormat::Hdr10 {
+        params.extend([String::from("hdr10=1"), String::from("hdr10-opt=1")]);
+    }
+    if let Some(display) = &video.mastering_display {
+        params.push(format!("master-display={}", master_display(display)));
+    }
+    if let Some(light) = &video.content_light_level {
+        params.push(format!("max-cll={},{}", light.max_content, light.max_average));
+    }
+    params
+}
+
+/// x265 wants chromaticities in units of 0.00002 and luminance in units of
+/// 0.0001 cd/m².
+fn master_display(display: &MasteringDisplay) -> String {
+    let xy = |(x, y): (f64, f64)| format!("({},{})", (x * 50000.0).round(), (y * 50000.0).round());
+    format!("G{}B{}R{}WP{}L({},{})",
+        xy(display.green), xy(display.blue), xy(display.red), xy(display.white_point),
+        (display.max_luminance * 10000.0).round(), (display.min_luminance * 10000.0).round())
+}
+
+/// Keep the source's depth, within the 8, 10 and 12 bits x265 can encode.
+fn output_bit_depth(source: u8) -> u8 {
+    match source {
+        0..=8 => 8,
+        9..=10 => 10,
+        _ => 12,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffmpeg::parameter_factories::crf_table::CrfTable;
+
+    #[test]
+    fn test_default_crf_table() {
+        let table = CrfTable::parse(CRF_DEFAULTS.table).unwrap();
+        assert_eq!(table.lookup(1920, 1080, 24.0), 20);
+    }
+
+    #[test]
+    fn test_master_display() {
+        let display = MasteringDisplay {
+            red: (0.68, 0.32),
+            green: (0.265, 0.69),
+            blue: (0.15, 0.06),
+            white_point: (0.3127, 0.329),
+            min_luminance: 0.005,
+            max_luminance: 1000.0,
+        };
+        assert_eq!(master_display(&display), "G(13250,34500)B(7500,3000)R(34000,16000)WP(15635,16450)L(10000000,50)");
     }
 }
@@ -0,0 +1,218 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use crate::ffmpeg::grain::FilmGrainMode;
+use crate::fstools::config_dir;
+
+pub const DEFAULT_QUALITY_PRESET: &str = "balanced";
+
+/// libx265's `-preset` names, fastest first.
+pub const X265_PRESETS: [&str; 10] = [
+    "ultrafast", "superfast", "veryfast", "faster", "fast",
+    "medium", "slow", "slower", "veryslow", "placebo",
+];
+const X265_TUNES: [&str; 6] = ["psnr", "ssim", "grain", "zerolatency", "fastdecode", "animation"];
+/// SVT-AV1's presets run from -2 (slowest) to 13 (fastest).
+pub const SVTAV1_PRESETS: RangeInclusive<i8> = -2..=13;
+
+/// How one quality preset drives libsvtav1.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Av1Settings {
+    /// Added to the CRF from the resolution table.
+    pub crf_offset: i32,
+    pub preset: i8,
+    /// 0 tunes for visual quality, 1 for PSNR, 2 for SSIM.
+    pub tune: u8,
+}
+
+impl Default for Av1Settings {
+    fn default() -> Self {
+        Av1Settings { crf_offset: 0, preset: 2, tune: 0 }
+    }
+}
+
+/// How one quality preset drives libx265.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HevcSettings {
+    /// Added to the CRF from the resolution table.
+    pub crf_offset: i32,
+    pub preset: String,
+    /// Animation sources are always tuned for animation instead.
+    pub tune: Option<String>,
+}
+
+impl Default for HevcSettings {
+    fn default() -> Self {
+        HevcSettings { crf_offset: 0, preset: String::from("slower"), tune: None }
+    }
+}
+
+/// A named trade-off between size, quality and encoding time. Settings left
+/// out of a user-defined preset take the `balanced` values; the film grain
+/// and size limits apply unless given on the command line.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QualityPreset {
+    pub description: String,
+    pub av1: Av1Settings,
+    pub hevc: HevcSettings,
+    /// As for --film-grain.
+    pub film_grain: Option<String>,
+    pub max_width: Option<u64>,
+    pub max_height: Option<u64>,
+}
+
+impl QualityPreset {
+    pub fn film_grain(&self) -> Option<FilmGrainMode> {
+        self.film_grain.as_deref().and_then(FilmGrainMode::parse)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if !SVTAV1_PRESETS.contains(&self.av1.preset) {
+            return Err(format!("av1 preset {} is outside {}..={}", self.av1.preset, SVTAV1_PRESETS.start(), SVTAV1_PRESETS.end()));
+        }
+        if self.av1.tune > 2 {
+            return Err(format!("av1 tune {} is not 0, 1 or 2", self.av1.tune));
+        }
+        if !X265_PRESETS.contains(&self.hevc.preset.as_str()) {
+            return Err(format!("hevc preset {:?} is not one of {}", self.hevc.preset, X265_PRESETS.join(", ")));
+        }
+        if let Some(tune) = &self.hevc.tune && !X265_TUNES.contains(&tune.as_str()) {
+            return Err(format!("hevc tune {:?} is not one of {}", tune, X265_TUNES.join(", ")));
+        }
+        if let Some(film_grain) = &self.film_grain && FilmGrainMode::parse(film_grain).is_none() {
+            return Err(format!("film grain {:?} is not auto, off or a level 0-50", film_grain));
+        }
+        if self.max_width.is_some_and(|width| width < 2) || self.max_height.is_some_and(|height| height < 2) {
+            return Err(String::from("max width and height must be at least 2"));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for QualityPreset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "av1 crf{:+} preset {} tune {}; hevc crf{:+} preset {}",
+            self.av1.crf_offset, self.av1.preset, self.av1.tune,
+            self.hevc.crf_offset, self.hevc.preset)?;
+        if let Some(tune) = &self.hevc.tune {
+            write!(f, " tune {}", tune)?;
+        }
+        if let Some(film_grain) = &self.film_grain {
+            write!(f, "; film grain {}", film_grain)?;
+        }
+        match (self.max_width, self.max_height) {
+            (Some(width), Some(height)) => write!(f, "; at most {}x{}", width, height),
+            (Some(width), None) => write!(f, "; at most {} wide", width),
+            (None, Some(height)) => write!(f, "; at most {} high", height),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+/// The built-in presets, overridden and extended by the user's config file.
+#[derive(Clone, Debug)]
+pub struct QualityPresets {
+    presets: BTreeMap<String, QualityPreset>,
+}
+
+impl QualityPresets {
+    /// `$XDG_CONFIG_HOME/compress-mkv/quality-presets.json`, falling back to
+    /// `~/.config/compress-mkv/quality-presets.json`.
+    pub fn default_path() -> Option<PathBuf> {
+        config_dir().map(|dir| dir.join("quality-presets.json"))
+    }
+
+    pub fn builtin() -> Self {
+        let mut presets = BTreeMap::new();
+        presets.insert(String::from("archive"), QualityPreset {
+            description: String::from("Visually transparent copies to keep; slow."),
+            av1: Av1Settings { crf_offset: -7, preset: 2, tune: 0 },
+            hevc: HevcSettings { crf_offset: -2, preset: String::from("veryslow"), tune: Some(String::from("grain")) },
+            ..QualityPreset::default()
+        });
+        presets.insert(String::from(DEFAULT_QUALITY_PRESET), QualityPreset {
+            description: String::from("Good quality at a good size for a media server."),
+            ..QualityPreset::default()
+        });
+        presets.insert(String::from("fast"), QualityPreset {
+            description: String::from("Quick, smaller encodes where quality matters less."),
+            av1: Av1Settings { crf_offset: 10, preset: 12, tune: 0 },
+            hevc: HevcSettings { crf_offset: 15, preset: String::from("veryfast"), tune: None },
+            ..QualityPreset::default()
+        });
+        presets.insert(String::from("mobile"), QualityPreset {
+            description: String::from("Small 720p copies for phones and tablets."),
+            av1: Av1Settings { crf_offset: 6, preset: 8, tune: 0 },
+            hevc: HevcSettings { crf_offset: 5, preset: String::from("medium"), tune: None },
+            film_grain: Some(String::from("off")),
+            max_height: Some(720),
+            ..QualityPreset::default()
+        });
+        QualityPresets { presets }
+    }
+
+    /// The built-in presets plus those in `path`, if it exists.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let mut presets = QualityPresets::builtin();
+        let json = match fs::read_to_string(path) {
+            Ok(json) => json,
+            Err(_) => return Ok(presets),
+        };
+        let user: BTreeMap<String, QualityPreset> = serde_json::from_str(&json)
+            .map_err(|err| format!("unable to read {:?}; {}", path, err))?;
+        for (name, preset) in user {
+            preset.validate().map_err(|err| format!("quality preset {:?} in {:?}: {}", name, path, err))?;
+            presets.presets.insert(name.to_lowercase(), preset);
+        }
+        Ok(presets)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&QualityPreset> {
+        self.presets.get(&name.to_lowercase())
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.presets.keys().map(String::as_str).collect()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &QualityPreset)> {
+        self.presets.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_presets() {
+        let presets = QualityPresets::builtin();
+        assert_eq!(presets.names(), vec!["archive", "balanced", "fast", "mobile"]);
+        assert!(presets.iter().all(|(_, preset)| preset.validate().is_ok()));
+        assert_eq!(presets.get("Mobile").unwrap().max_height, Some(720));
+        assert_eq!(presets.get("mobile").unwrap().film_grain(), Some(FilmGrainMode::Off));
+        assert_eq!(presets.get("balanced").unwrap().film_grain(), None);
+        assert!(presets.get("extreme").is_none());
+    }
+
+    #[test]
+    fn test_user_preset() {
+        let preset: QualityPreset = serde_json::from_str(r#"{"av1": {"preset": 6}, "hevc": {"tune": "grain"}}"#).unwrap();
+        assert_eq!(preset.av1, Av1Settings { crf_offset: 0, preset: 6, tune: 0 });
+        assert_eq!(preset.hevc.preset, "slower");
+        assert!(preset.validate().is_ok());
+
+        let preset: QualityPreset = serde_json::from_str(r#"{"av1": {"preset": 14}}"#).unwrap();
+        assert!(preset.validate().is_err());
+        let preset: QualityPreset = serde_json::from_str(r#"{"hevc": {"preset": "quick"}}"#).unwrap();
+        assert!(preset.validate().is_err());
+        let preset: QualityPreset = serde_json::from_str(r#"{"film_grain": "lots"}"#).unwrap();
+        assert!(preset.validate().is_err());
+    }
+}
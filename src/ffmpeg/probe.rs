@@ -1,20 +1,176 @@
-use std::error::Error;
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use serde::{Deserialize, Serialize};
 use serde_json;
 
-use crate::error::InputParseError;
+use crate::error::{InputParseError, InputParseReason};
 
-#[derive(Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct AVProbeMetadata {
     pub video_codec: String,
     pub video_codec_tag: String,
     pub width: u64,
     pub height: u64,
     pub total_frames: usize,
+    pub frame_count_source: FrameCountSource,
     pub frame_rate: u64,
     pub interlaced: bool,
+    pub pix_fmt: Option<String>,
+    pub bit_depth: u8,
+    pub chroma_subsampling: ChromaSubsampling,
+    pub format: AVFormat,
+    pub streams: Vec<AVStream>,
+    pub chapters: Vec<AVChapter>,
+}
+
+/// Where `AVProbeMetadata::total_frames` came from, from most to least
+/// trustworthy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FrameCountSource {
+    /// Every packet was counted (`-count_packets`).
+    Counted,
+    /// The container told us (`nb_frames` or a `NUMBER_OF_FRAMES` tag).
+    Container,
+    /// Derived from duration and frame rate.
+    Estimated,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChromaSubsampling {
+    Yuv420,
+    Yuv422,
+    Yuv444,
+    /// Greyscale, no chroma at all.
+    Mono,
+    /// RGB, planar oddities like 4:1:1, or a pix_fmt we don't recognise.
+    Other,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct AVFormat {
+    pub format_name: String,
+    pub format_long_name: String,
+    pub duration: Option<f64>,
+    pub size: Option<u64>,
+    pub bit_rate: Option<u64>,
+    pub title: Option<String>,
+    pub tags: HashMap<String, String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AVStream {
+    pub index: usize,
+    pub codec_name: String,
+    pub codec_long_name: String,
+    pub codec_tag: String,
+    pub language: Option<String>,
+    pub title: Option<String>,
+    pub disposition: AVDisposition,
+    pub bit_rate: Option<u64>,
+    pub duration: Option<f64>,
+    pub frame_count: Option<usize>,
+    pub kind: AVStreamKind,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum AVStreamKind {
+    Video(Box<AVVideoStream>),
+    Audio(AVAudioStream),
+    Subtitle,
+    Attachment(AVAttachment),
+    Data,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct AVVideoStream {
+    pub width: u64,
+    pub height: u64,
+    pub profile: Option<String>,
+    pub pix_fmt: Option<String>,
+    pub bit_depth: Option<u8>,
+    pub frame_rate: Option<f64>,
+    pub field_order: Option<String>,
+    pub color_range: Option<String>,
+    pub color_space: Option<String>,
+    pub color_transfer: Option<String>,
+    pub color_primaries: Option<String>,
+    pub mastering_display: Option<MasteringDisplay>,
+    pub content_light_level: Option<ContentLightLevel>,
+    pub dolby_vision: Option<DolbyVision>,
+}
+
+/// SMPTE ST 2086 mastering display colour volume: CIE 1931 xy chromaticity
+/// coordinates and luminance in cd/m².
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MasteringDisplay {
+    pub red: (f64, f64),
+    pub green: (f64, f64),
+    pub blue: (f64, f64),
+    pub white_point: (f64, f64),
+    pub min_luminance: f64,
+    pub max_luminance: f64,
+}
+
+/// MaxCLL and MaxFALL, in cd/m².
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContentLightLevel {
+    pub max_content: u32,
+    pub max_average: u32,
+}
+
+/// A Dolby Vision decoder configuration record.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DolbyVision {
+    pub profile: u8,
+    pub level: u8,
+    pub rpu_present: bool,
+    pub el_present: bool,
+    pub bl_present: bool,
+    /// What the base layer is compatible with: 1 HDR10, 2 SDR, 4 HLG, 6 Blu-ray HDR10.
+    pub bl_compatibility_id: u8,
+}
+
+/// High dynamic range transfer functions we know how to carry through.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HdrFormat {
+    /// SMPTE ST 2084 (PQ), usually with static metadata.
+    Hdr10,
+    /// ARIB STD-B67 hybrid log-gamma.
+    Hlg,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct AVAudioStream {
+    pub profile: Option<String>,
+    pub channels: Option<u32>,
+    pub channel_layout: Option<String>,
+    pub sample_rate: Option<u32>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct AVAttachment {
+    pub filename: Option<String>,
+    pub mimetype: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct AVDisposition {
+    pub default: bool,
+    pub forced: bool,
+    pub original: bool,
+    pub dub: bool,
+    pub comment: bool,
+    pub hearing_impaired: bool,
+    pub visual_impaired: bool,
+    pub attached_pic: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AVChapter {
+    pub start: f64,
+    pub end: f64,
+    pub title: Option<String>,
 }
 
 impl AVProbeMetadata {
@@ -25,78 +181,546 @@ impl AVProbeMetadata {
             width: 0,
             height: 0,
             total_frames: 0,
+            frame_count_source: FrameCountSource::Estimated,
             frame_rate: 300,
             interlaced: false,
+            pix_fmt: None,
+            bit_depth: 8,
+            chroma_subsampling: ChromaSubsampling::Yuv420,
+            format: AVFormat::default(),
+            streams: Vec::new(),
+            chapters: Vec::new(),
+        }
+    }
+
+    /// Duration in seconds, preferring the container's idea of it.
+    pub fn duration(&self) -> Option<f64> {
+        self.format.duration.or(self.primary_video_stream().and_then(|s| s.duration))
+    }
+
+    /// The unrounded frame rate of the primary video stream.
+    pub fn exact_frame_rate(&self) -> f64 {
+        self.primary_video_stream()
+            .and_then(|s| s.video())
+            .and_then(|v| v.frame_rate)
+            .unwrap_or(self.frame_rate as f64)
+    }
+
+    /// The primary video stream's video details.
+    pub fn video(&self) -> Option<&AVVideoStream> {
+        self.primary_video_stream().and_then(|s| s.video())
+    }
+
+    pub fn hdr_format(&self) -> Option<HdrFormat> {
+        match self.video()?.color_transfer.as_deref()? {
+            "smpte2084" => Some(HdrFormat::Hdr10),
+            "arib-std-b67" => Some(HdrFormat::Hlg),
+            _ => None,
+        }
+    }
+
+    /// The stream we consider "the video": the first video stream that is
+    /// not cover art.
+    pub fn primary_video_stream(&self) -> Option<&AVStream> {
+        self.video_streams().find(|s| !s.disposition.attached_pic)
+    }
+
+    pub fn video_streams(&self) -> impl Iterator<Item = &AVStream> {
+        self.streams.iter().filter(|s| matches!(s.kind, AVStreamKind::Video(_)))
+    }
+
+    pub fn audio_streams(&self) -> impl Iterator<Item = &AVStream> {
+        self.streams.iter().filter(|s| matches!(s.kind, AVStreamKind::Audio(_)))
+    }
+
+    pub fn subtitle_streams(&self) -> impl Iterator<Item = &AVStream> {
+        self.streams.iter().filter(|s| matches!(s.kind, AVStreamKind::Subtitle))
+    }
+
+    pub fn attachment_streams(&self) -> impl Iterator<Item = &AVStream> {
+        self.streams.iter().filter(|s| matches!(s.kind, AVStreamKind::Attachment(_)))
+    }
+
+    pub fn data_streams(&self) -> impl Iterator<Item = &AVStream> {
+        self.streams.iter().filter(|s| matches!(s.kind, AVStreamKind::Data))
+    }
+}
+
+impl AVStream {
+    pub fn video(&self) -> Option<&AVVideoStream> {
+        match &self.kind {
+            AVStreamKind::Video(video) => Some(video.as_ref()),
+            _ => None,
+        }
+    }
+
+    pub fn audio(&self) -> Option<&AVAudioStream> {
+        match &self.kind {
+            AVStreamKind::Audio(audio) => Some(audio),
+            _ => None,
+        }
+    }
+
+    /// A bare stream for tests; everything but the codec and kind is empty.
+    #[cfg(test)]
+    pub fn for_test(index: usize, codec_name: &str, kind: AVStreamKind) -> AVStream {
+        AVStream {
+            index,
+            codec_name: String::from(codec_name),
+            codec_long_name: String::new(),
+            codec_tag: String::new(),
+            language: None,
+            title: None,
+            disposition: AVDisposition::default(),
+            bit_rate: None,
+            duration: None,
+            frame_count: None,
+            kind,
         }
     }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 struct FFProbeJsonOutput {
+    #[serde(default)]
     pub streams: Vec<FFProbeJsonStream>,
+    pub format: Option<FFProbeJsonFormat>,
+    #[serde(default)]
+    pub chapters: Vec<FFProbeJsonChapter>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
+struct FFProbeJsonFormat {
+    pub format_name: Option<String>,
+    pub format_long_name: Option<String>,
+    pub duration: Option<String>,
+    pub size: Option<String>,
+    pub bit_rate: Option<String>,
+    #[serde(default)]
+    pub tags: HashMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
 struct FFProbeJsonStream {
-    pub codec_name: String,
-    pub codec_tag_string: String,
-    pub width: u64,
-    pub height: u64,
-    pub pix_fmt: String,
-    pub nb_read_packets: String,
-    pub avg_frame_rate: String,
+    #[serde(default)]
+    pub index: usize,
+    pub codec_type: Option<String>,
+    pub codec_name: Option<String>,
+    pub codec_long_name: Option<String>,
+    pub codec_tag_string: Option<String>,
+    pub profile: Option<String>,
+    pub width: Option<u64>,
+    pub height: Option<u64>,
+    pub pix_fmt: Option<String>,
+    pub bits_per_raw_sample: Option<String>,
     pub field_order: Option<String>,
+    pub color_range: Option<String>,
+    pub color_space: Option<String>,
+    pub color_transfer: Option<String>,
+    pub color_primaries: Option<String>,
+    pub channels: Option<u32>,
+    pub channel_layout: Option<String>,
+    pub sample_rate: Option<String>,
+    pub avg_frame_rate: Option<String>,
+    pub r_frame_rate: Option<String>,
+    pub duration: Option<String>,
+    pub bit_rate: Option<String>,
+    pub nb_frames: Option<String>,
+    pub nb_read_packets: Option<String>,
+    #[serde(default)]
+    pub disposition: HashMap<String, i64>,
+    #[serde(default)]
+    pub tags: HashMap<String, String>,
+    #[serde(default)]
+    pub side_data_list: Vec<HashMap<String, serde_json::Value>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct FFProbeJsonFrames {
+    #[serde(default)]
+    pub frames: Vec<FFProbeJsonFrame>,
 }
 
-pub fn probe_file(path: &PathBuf) -> Result<AVProbeMetadata, Box<dyn Error>> { 
+#[derive(Serialize, Deserialize, Debug)]
+struct FFProbeJsonFrame {
+    #[serde(default)]
+    pub side_data_list: Vec<HashMap<String, serde_json::Value>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct FFProbeJsonChapter {
+    pub start_time: Option<String>,
+    pub end_time: Option<String>,
+    #[serde(default)]
+    pub tags: HashMap<String, String>,
+}
+
+/// Probe `path` with ffprobe. Unless `count_packets` is set the frame count
+/// is taken from the container or estimated, which avoids reading the
+/// entire file.
+pub fn probe_file(path: &PathBuf, count_packets: bool) -> Result<AVProbeMetadata, InputParseError> {
     println!("probing {:?}", path);
+    let mut args = vec![
+        PathBuf::from("-v"),
+        PathBuf::from("error"),
+        PathBuf::from("-of"),
+        PathBuf::from("json"),
+        PathBuf::from("-show_format"),
+        PathBuf::from("-show_streams"),
+        PathBuf::from("-show_chapters"),
+    ];
+    if count_packets {
+        args.push(PathBuf::from("-count_packets"));
+    }
+    args.push(path.clone());
+    let output = Command::new("ffprobe")
+        .args(args)
+        .output()
+        .map_err(|err| InputParseError::with_reason(path, InputParseReason::Unreadable, &format!("unable to run ffprobe: {err}")))?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        let reason = classify_ffprobe_stderr(&stderr).unwrap_or(InputParseReason::Unreadable);
+        return Err(InputParseError::with_reason(path, reason, &format!("ffprobe did not exit successfully. {}", stderr.trim())));
+    }
+    if let Some(InputParseReason::Truncated) = classify_ffprobe_stderr(&stderr) {
+        return Err(InputParseError::with_reason(path, InputParseReason::Truncated, stderr.trim()));
+    }
+
+    let utf8 = String::from_utf8(output.stdout)
+        .map_err(|_| InputParseError::for_file(path, "ffprobe output is not valid UTF-8."))?;
+    let deserialized = serde_json::from_str::<FFProbeJsonOutput>(&utf8)
+        .map_err(|err| InputParseError::for_file(path, &format!("unable to parse ffprobe output: {err}")))?;
+    let mut probe = parse_probe_output(path, deserialized)?;
+
+    // mp4 and some mkv muxers only carry HDR metadata in the bitstream
+    if probe.hdr_format().is_some()
+        && let Some(stream) = probe.streams.iter_mut().find(|s| s.video().is_some() && !s.disposition.attached_pic)
+        && let AVStreamKind::Video(video) = &mut stream.kind
+        && video.mastering_display.is_none() && video.content_light_level.is_none() {
+        let (mastering_display, content_light_level) = probe_first_frame_side_data(path, stream.index);
+        video.mastering_display = mastering_display;
+        video.content_light_level = content_light_level;
+    }
+    Ok(probe)
+}
+
+fn probe_first_frame_side_data(path: &Path, index: usize) -> (Option<MasteringDisplay>, Option<ContentLightLevel>) {
     let output = Command::new("ffprobe")
         .args([
-            &PathBuf::from("-of"),
-            &PathBuf::from("json"),
-            &PathBuf::from("-show_streams"),
-            &PathBuf::from("-select_streams"),
-            &PathBuf::from("v:0"),
-            &PathBuf::from("-count_packets"),
-            path,
+            PathBuf::from("-v"), PathBuf::from("error"),
+            PathBuf::from("-of"), PathBuf::from("json"),
+            PathBuf::from("-select_streams"), PathBuf::from(index.to_string()),
+            PathBuf::from("-read_intervals"), PathBuf::from("%+#1"),
+            PathBuf::from("-show_entries"), PathBuf::from("frame=side_data_list"),
+            path.to_path_buf(),
         ])
-        .output()?;
-    if output.status.success() {
-        let utf8 = String::from_utf8(output.stdout)?;
-        let deserialized = serde_json::from_str::<FFProbeJsonOutput>(&utf8)?;
-        let field_order = match &deserialized.streams[0].field_order {
-            Some(s) => s,
-            None => "progressive",
-        };
-        Ok(AVProbeMetadata {
-            video_codec: deserialized.streams[0].codec_name.clone(),
-            video_codec_tag: deserialized.streams[0].codec_tag_string.clone(),
-            width: deserialized.streams[0].width,
-            height: deserialized.streams[0].height,
-            total_frames: deserialized.streams[0].nb_read_packets.parse().unwrap_or(1),
-            frame_rate: get_frame_rate(path, &deserialized.streams[0]).unwrap_or(300),
-            interlaced: field_order != "progressive",
-        })
+        .output();
+    match output.ok().and_then(|output| serde_json::from_slice::<FFProbeJsonFrames>(&output.stdout).ok()) {
+        Some(frames) => match frames.frames.first() {
+            Some(frame) => parse_side_data(&frame.side_data_list),
+            None => (None, None),
+        },
+        None => (None, None),
+    }
+}
+
+/// ffprobe reports most problems through its exit status, but truncated
+/// files often probe "successfully" with only a complaint on stderr.
+fn classify_ffprobe_stderr(stderr: &str) -> Option<InputParseReason> {
+    let stderr = stderr.to_lowercase();
+    if ["partial file", "ended prematurely", "moov atom not found", "truncat", "end of file"]
+        .iter().any(|marker| stderr.contains(marker)) {
+        Some(InputParseReason::Truncated)
+    } else if ["no such file", "permission denied", "invalid data found", "is a directory"]
+        .iter().any(|marker| stderr.contains(marker)) {
+        Some(InputParseReason::Unreadable)
     } else {
-        Err(Box::new(InputParseError::for_file(path, "ffprobe did not exit successfully.")))
+        None
+    }
+}
+
+fn parse_probe_output(path: &PathBuf, output: FFProbeJsonOutput) -> Result<AVProbeMetadata, InputParseError> {
+    let streams: Vec<AVStream> = output.streams.iter().map(|s| parse_stream(path, s)).collect();
+    let chapters = output.chapters.iter().map(parse_chapter).collect();
+    let format = match output.format {
+        Some(format) => parse_format(format),
+        None => AVFormat::default(),
+    };
+
+    let primary = streams.iter()
+        .position(|s| s.video().is_some() && !s.disposition.attached_pic)
+        .ok_or_else(|| InputParseError::with_reason(path, InputParseReason::NoVideoStream, "no video stream found."))?;
+    let json_stream = &output.streams[primary];
+    let stream = &streams[primary];
+    let video = match stream.video() {
+        Some(video) => video,
+        None => return Err(InputParseError::with_reason(path, InputParseReason::NoVideoStream, "no video stream found.")),
+    };
+    if stream.codec_name.is_empty() || stream.codec_name == "none" {
+        return Err(InputParseError::with_reason(path, InputParseReason::UnknownCodec, &format!("unknown video codec in stream {}.", stream.index)));
+    }
+    let field_order = match &video.field_order {
+        Some(s) => s,
+        None => "progressive",
+    };
+
+    let (pix_fmt_depth, chroma_subsampling) = video.pix_fmt.as_deref()
+        .and_then(parse_pix_fmt)
+        .unwrap_or((8, ChromaSubsampling::Other));
+
+    let duration = format.duration.or(stream.duration);
+    let (total_frames, frame_count_source) = match (&json_stream.nb_read_packets, stream.frame_count) {
+        (Some(_), Some(count)) => (count, FrameCountSource::Counted),
+        (None, Some(count)) => (count, FrameCountSource::Container),
+        _ => (estimate_frame_count(duration, video.frame_rate), FrameCountSource::Estimated),
+    };
+
+    Ok(AVProbeMetadata {
+        video_codec: stream.codec_name.clone(),
+        video_codec_tag: stream.codec_tag.clone(),
+        width: video.width,
+        height: video.height,
+        total_frames,
+        frame_count_source,
+        frame_rate: get_frame_rate(path, json_stream).unwrap_or(300),
+        interlaced: field_order != "progressive" && field_order != "unknown",
+        pix_fmt: video.pix_fmt.clone(),
+        bit_depth: video.bit_depth.unwrap_or(pix_fmt_depth),
+        chroma_subsampling,
+        format,
+        streams,
+        chapters,
+    })
+}
+
+fn parse_format(format: FFProbeJsonFormat) -> AVFormat {
+    AVFormat {
+        format_name: format.format_name.unwrap_or_default(),
+        format_long_name: format.format_long_name.unwrap_or_default(),
+        duration: parse_number(&format.duration),
+        size: parse_number(&format.size),
+        bit_rate: parse_number(&format.bit_rate),
+        title: tag(&format.tags, "title"),
+        tags: format.tags,
+    }
+}
+
+fn parse_stream(path: &PathBuf, stream: &FFProbeJsonStream) -> AVStream {
+    let (mastering_display, content_light_level) = parse_side_data(&stream.side_data_list);
+    let kind = match stream.codec_type.as_deref() {
+        Some("video") => AVStreamKind::Video(Box::new(AVVideoStream {
+            width: stream.width.unwrap_or(0),
+            height: stream.height.unwrap_or(0),
+            profile: stream.profile.clone(),
+            pix_fmt: stream.pix_fmt.clone(),
+            bit_depth: parse_number(&stream.bits_per_raw_sample),
+            frame_rate: parse_frame_rate(path, stream).ok(),
+            field_order: stream.field_order.clone(),
+            color_range: stream.color_range.clone(),
+            color_space: stream.color_space.clone(),
+            color_transfer: stream.color_transfer.clone(),
+            color_primaries: stream.color_primaries.clone(),
+            mastering_display,
+            content_light_level,
+            dolby_vision: parse_dolby_vision(&stream.side_data_list),
+        })),
+        Some("audio") => AVStreamKind::Audio(AVAudioStream {
+            profile: stream.profile.clone(),
+            channels: stream.channels,
+            channel_layout: stream.channel_layout.clone(),
+            sample_rate: parse_number(&stream.sample_rate),
+        }),
+        Some("subtitle") => AVStreamKind::Subtitle,
+        Some("attachment") => AVStreamKind::Attachment(AVAttachment {
+            filename: tag(&stream.tags, "filename"),
+            mimetype: tag(&stream.tags, "mimetype"),
+        }),
+        _ => AVStreamKind::Data,
+    };
+
+    AVStream {
+        index: stream.index,
+        codec_name: stream.codec_name.clone().unwrap_or_default(),
+        codec_long_name: stream.codec_long_name.clone().unwrap_or_default(),
+        codec_tag: stream.codec_tag_string.clone().unwrap_or_default(),
+        language: tag(&stream.tags, "language"),
+        title: tag(&stream.tags, "title"),
+        disposition: parse_disposition(&stream.disposition),
+        bit_rate: parse_number(&stream.bit_rate),
+        duration: parse_number(&stream.duration),
+        frame_count: parse_number(&stream.nb_read_packets)
+            .or(parse_number(&stream.nb_frames))
+            .or(tag_prefixed(&stream.tags, "NUMBER_OF_FRAMES").and_then(|n| n.parse().ok())),
+        kind,
+    }
+}
+
+fn parse_disposition(disposition: &HashMap<String, i64>) -> AVDisposition {
+    let flag = |name: &str| disposition.get(name).is_some_and(|v| *v != 0);
+    AVDisposition {
+        default: flag("default"),
+        forced: flag("forced"),
+        original: flag("original"),
+        dub: flag("dub"),
+        comment: flag("comment"),
+        hearing_impaired: flag("hearing_impaired"),
+        visual_impaired: flag("visual_impaired"),
+        attached_pic: flag("attached_pic"),
+    }
+}
+
+fn parse_chapter(chapter: &FFProbeJsonChapter) -> AVChapter {
+    AVChapter {
+        start: parse_number(&chapter.start_time).unwrap_or(0.0),
+        end: parse_number(&chapter.end_time).unwrap_or(0.0),
+        title: tag(&chapter.tags, "title"),
+    }
+}
+
+/// Look up a tag case-insensitively; matroska tags are usually upper case
+/// while mp4 tags are lower case.
+fn tag(tags: &HashMap<String, String>, name: &str) -> Option<String> {
+    tags.iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.clone())
+}
+
+/// mkvmerge writes its statistics tags with a language suffix, e.g.
+/// `NUMBER_OF_FRAMES-eng`.
+fn tag_prefixed(tags: &HashMap<String, String>, prefix: &str) -> Option<String> {
+    tags.iter()
+        .find(|(k, _)| k.to_uppercase().starts_with(prefix))
+        .map(|(_, v)| v.clone())
+}
+
+/// Mastering display and content light level from a stream's or frame's
+/// side data.
+fn parse_side_data(side_data: &[HashMap<String, serde_json::Value>]) -> (Option<MasteringDisplay>, Option<ContentLightLevel>) {
+    let find = |kind: &str| side_data.iter()
+        .find(|data| data.get("side_data_type").and_then(|t| t.as_str()) == Some(kind));
+    let mastering_display = find("Mastering display metadata").and_then(|data| {
+        let value = |key: &str| data.get(key).and_then(parse_rational);
+        Some(MasteringDisplay {
+            red: (value("red_x")?, value("red_y")?),
+            green: (value("green_x")?, value("green_y")?),
+            blue: (value("blue_x")?, value("blue_y")?),
+            white_point: (value("white_point_x")?, value("white_point_y")?),
+            min_luminance: value("min_luminance")?,
+            max_luminance: value("max_luminance")?,
+        })
+    });
+    let content_light_level = find("Content light level metadata").and_then(|data| {
+        let value = |key: &str| data.get(key).and_then(parse_rational);
+        Some(ContentLightLevel {
+            max_content: value("max_content")? as u32,
+            max_average: value("max_average")? as u32,
+        })
+    });
+    (mastering_display, content_light_level)
+}
+
+fn parse_dolby_vision(side_data: &[HashMap<String, serde_json::Value>]) -> Option<DolbyVision> {
+    let data = side_data.iter()
+        .find(|data| data.get("side_data_type").and_then(|t| t.as_str()) == Some("DOVI configuration record"))?;
+    let value = |key: &str| data.get(key).and_then(parse_rational).map(|value| value as u8);
+    Some(DolbyVision {
+        profile: value("dv_profile")?,
+        level: value("dv_level").unwrap_or(0),
+        rpu_present: value("rpu_present_flag") == Some(1),
+        el_present: value("el_present_flag") == Some(1),
+        bl_present: value("bl_present_flag") == Some(1),
+        bl_compatibility_id: value("dv_bl_signal_compatibility_id").unwrap_or(0),
+    })
+}
+
+/// ffprobe writes side data values as numbers or as `num/den` strings.
+fn parse_rational(value: &serde_json::Value) -> Option<f64> {
+    match value {
+        serde_json::Value::Number(number) => number.as_f64(),
+        serde_json::Value::String(string) => match string.split_once('/') {
+            Some((num, den)) => {
+                let (num, den) = (num.parse::<f64>().ok()?, den.parse::<f64>().ok()?);
+                (den != 0.0).then(|| num / den)
+            },
+            None => string.parse().ok(),
+        },
+        _ => None,
+    }
+}
+
+/// Bit depth and chroma subsampling from an ffmpeg pix_fmt name such as
+/// `yuv420p10le`, `yuvj422p`, `p010le` or `gray12le`.
+fn parse_pix_fmt(pix_fmt: &str) -> Option<(u8, ChromaSubsampling)> {
+    let depth = |digits: &str| match digits {
+        "" => Some(8),
+        digits => digits.parse().ok(),
+    };
+    let name = pix_fmt.trim_end_matches("le").trim_end_matches("be");
+    let chroma = |layout: &str| match layout {
+        "420" => ChromaSubsampling::Yuv420,
+        "422" => ChromaSubsampling::Yuv422,
+        "444" => ChromaSubsampling::Yuv444,
+        _ => ChromaSubsampling::Other,
+    };
+    if let Some(rest) = name.strip_prefix("yuvj").or(name.strip_prefix("yuva")).or(name.strip_prefix("yuv")) {
+        let (layout, bits) = rest.split_once('p')?;
+        return Some((depth(bits)?, chroma(layout)));
+    }
+    if let Some(bits) = name.strip_prefix("gray") {
+        return Some((depth(bits)?, ChromaSubsampling::Mono));
+    }
+    match name {
+        "nv12" | "nv21" => Some((8, ChromaSubsampling::Yuv420)),
+        "nv16" => Some((8, ChromaSubsampling::Yuv422)),
+        "nv24" | "nv42" => Some((8, ChromaSubsampling::Yuv444)),
+        "p010" => Some((10, ChromaSubsampling::Yuv420)),
+        "p012" => Some((12, ChromaSubsampling::Yuv420)),
+        "p016" => Some((16, ChromaSubsampling::Yuv420)),
+        "p210" => Some((10, ChromaSubsampling::Yuv422)),
+        "p410" => Some((10, ChromaSubsampling::Yuv444)),
+        _ => name.strip_prefix("gbrp").and_then(depth).map(|bits| (bits, ChromaSubsampling::Other)),
+    }
+}
+
+fn estimate_frame_count(duration: Option<f64>, frame_rate: Option<f64>) -> usize {
+    match (duration, frame_rate) {
+        (Some(duration), Some(frame_rate)) if duration > 0.0 && frame_rate > 0.0 => (duration * frame_rate).round() as usize,
+        _ => 1,
     }
 }
 
+fn parse_number<T: std::str::FromStr>(value: &Option<String>) -> Option<T> {
+    value.as_ref().and_then(|v| v.parse().ok())
+}
+
 fn get_frame_rate(path: &PathBuf, stream: &FFProbeJsonStream) -> Result<u64, InputParseError> {
-    let splits: Vec<&str> = stream.avg_frame_rate.split("/").collect();
+    Ok(parse_frame_rate(path, stream)?.round() as u64)
+}
+
+fn parse_frame_rate(path: &PathBuf, stream: &FFProbeJsonStream) -> Result<f64, InputParseError> {
+    let avg_frame_rate = match (&stream.avg_frame_rate, &stream.r_frame_rate) {
+        (Some(avg), _) if avg != "0/0" => avg,
+        (_, Some(r)) => r,
+        _ => return Err(InputParseError::for_file(path, "stream has no frame rate.")),
+    };
+    let splits: Vec<&str> = avg_frame_rate.split("/").collect();
     match splits.len() {
         2 => {
-            if let Ok(num) = splits[0].parse::<f32>() {
-                if let Ok(denom) = splits[1].parse::<f32>() {
-                    Ok((num / denom).round() as u64)
+            if let Ok(num) = splits[0].parse::<f64>() {
+                if let Ok(denom) = splits[1].parse::<f64>() {
+                    if denom == 0.0 {
+                        Err(InputParseError::for_file(path, &format!("denominator of '{}' is zero.", avg_frame_rate)))
+                    } else {
+                        Ok(num / denom)
+                    }
                 } else {
-                    Err(InputParseError::for_file(path, &format!("denominator '{}' from '{}' is not a number.", splits[1], &stream.avg_frame_rate)))
+                    Err(InputParseError::for_file(path, &format!("denominator '{}' from '{}' is not a number.", splits[1], avg_frame_rate)))
                 }
             } else {
-                    Err(InputParseError::for_file(path, &format!("numerator '{}' from '{}' is not a number.", splits[0], &stream.avg_frame_rate)))
+                    Err(InputParseError::for_file(path, &format!("numerator '{}' from '{}' is not a number.", splits[0], avg_frame_rate)))
             }
         },
-        _ => Err(InputParseError::for_file(path, &format!("Unexpected avg_frame_rate format: '{}'", stream.avg_frame_rate))),
+        _ => Err(InputParseError::for_file(path, &format!("Unexpected avg_frame_rate format: '{}'", avg_frame_rate))),
     }
 }
 
@@ -111,16 +735,139 @@ mod tests {
         assert_eq!(get_frame_rate(&PathBuf::from(""), &ffprobe_json_stream_from_frame_rate("60/1")).unwrap(), 60);
     }
 
+    #[test]
+    fn test_parse_probe_output() {
+        let json = r#"{
+            "streams": [
+                { "index": 0, "codec_type": "video", "codec_name": "mjpeg", "width": 600, "height": 900,
+                  "avg_frame_rate": "0/0", "r_frame_rate": "90000/1", "disposition": { "attached_pic": 1 } },
+                { "index": 1, "codec_type": "video", "codec_name": "h264", "codec_tag_string": "[0][0][0][0]",
+                  "width": 1920, "height": 1080, "pix_fmt": "yuv420p", "bits_per_raw_sample": "8",
+                  "avg_frame_rate": "24000/1001", "field_order": "progressive", "nb_read_packets": "1000",
+                  "disposition": { "default": 1, "attached_pic": 0 } },
+                { "index": 2, "codec_type": "audio", "codec_name": "ac3", "channels": 6,
+                  "channel_layout": "5.1(side)", "sample_rate": "48000",
+                  "tags": { "language": "eng", "title": "Surround" } },
+                { "index": 3, "codec_type": "subtitle", "codec_name": "subrip",
+                  "disposition": { "forced": 1 }, "tags": { "LANGUAGE": "ger" } },
+                { "index": 4, "codec_type": "attachment", "codec_name": "ttf",
+                  "tags": { "filename": "font.ttf", "mimetype": "font/ttf" } }
+            ],
+            "format": { "format_name": "matroska,webm", "duration": "41.708000", "size": "1000000",
+                        "bit_rate": "191809", "tags": { "title": "Sample" } },
+            "chapters": [ { "start_time": "0.000000", "end_time": "20.000000", "tags": { "title": "Chapter 1" } } ]
+        }"#;
+        let output = serde_json::from_str::<FFProbeJsonOutput>(json).unwrap();
+        let probe = parse_probe_output(&PathBuf::from(""), output).unwrap();
+
+        assert_eq!(probe.video_codec, "h264");
+        assert_eq!((probe.width, probe.height), (1920, 1080));
+        assert_eq!(probe.total_frames, 1000);
+        assert_eq!(probe.frame_count_source, FrameCountSource::Counted);
+        assert_eq!(probe.frame_rate, 24);
+        assert!(!probe.interlaced);
+        assert_eq!((probe.bit_depth, probe.chroma_subsampling), (8, ChromaSubsampling::Yuv420));
+        assert_eq!(probe.primary_video_stream().unwrap().index, 1);
+        assert_eq!(probe.video_streams().count(), 2);
+        assert_eq!(probe.audio_streams().next().unwrap().audio().unwrap().channels, Some(6));
+        assert_eq!(probe.audio_streams().next().unwrap().language.as_deref(), Some("eng"));
+        assert_eq!(probe.subtitle_streams().next().unwrap().language.as_deref(), Some("ger"));
+        assert!(probe.subtitle_streams().next().unwrap().disposition.forced);
+        assert_eq!(probe.attachment_streams().count(), 1);
+        assert_eq!(probe.format.duration, Some(41.708));
+        assert_eq!(probe.format.title.as_deref(), Some("Sample"));
+        assert_eq!(probe.chapters.len(), 1);
+    }
+
+    #[test]
+    fn test_parse_probe_output_failures() {
+        let audio_only = r#"{ "streams": [ { "index": 0, "codec_type": "audio", "codec_name": "flac" } ] }"#;
+        let output = serde_json::from_str::<FFProbeJsonOutput>(audio_only).unwrap();
+        assert_eq!(parse_probe_output(&PathBuf::from(""), output).unwrap_err().reason(), InputParseReason::NoVideoStream);
+
+        let unknown_codec = r#"{ "streams": [ { "index": 0, "codec_type": "video" } ], "format": {} }"#;
+        let output = serde_json::from_str::<FFProbeJsonOutput>(unknown_codec).unwrap();
+        assert_eq!(parse_probe_output(&PathBuf::from(""), output).unwrap_err().reason(), InputParseReason::UnknownCodec);
+
+        let empty = serde_json::from_str::<FFProbeJsonOutput>("{}").unwrap();
+        assert_eq!(parse_probe_output(&PathBuf::from(""), empty).unwrap_err().reason(), InputParseReason::NoVideoStream);
+    }
+
+    #[test]
+    fn test_frame_count_without_counting_packets() {
+        let tagged = r#"{ "streams": [ { "index": 0, "codec_type": "video", "codec_name": "h264",
+            "avg_frame_rate": "24/1", "tags": { "NUMBER_OF_FRAMES-eng": "2400" } } ],
+            "format": { "duration": "100.5" } }"#;
+        let probe = parse_probe_output(&PathBuf::from(""), serde_json::from_str(tagged).unwrap()).unwrap();
+        assert_eq!((probe.total_frames, probe.frame_count_source), (2400, FrameCountSource::Container));
+
+        let untagged = r#"{ "streams": [ { "index": 0, "codec_type": "video", "codec_name": "h264",
+            "avg_frame_rate": "24000/1001" } ], "format": { "duration": "100.1" } }"#;
+        let probe = parse_probe_output(&PathBuf::from(""), serde_json::from_str(untagged).unwrap()).unwrap();
+        assert_eq!((probe.total_frames, probe.frame_count_source), (2400, FrameCountSource::Estimated));
+    }
+
+    #[test]
+    fn test_hdr_side_data() {
+        let json = r#"{ "streams": [ { "index": 0, "codec_type": "video", "codec_name": "hevc",
+            "avg_frame_rate": "24000/1001", "pix_fmt": "yuv420p10le", "color_transfer": "smpte2084",
+            "color_primaries": "bt2020", "color_space": "bt2020nc",
+            "side_data_list": [
+                { "side_data_type": "Mastering display metadata",
+                  "red_x": "34000/50000", "red_y": "16000/50000", "green_x": "13250/50000", "green_y": "34500/50000",
+                  "blue_x": "7500/50000", "blue_y": "3000/50000", "white_point_x": "15635/50000", "white_point_y": "16450/50000",
+                  "min_luminance": "50/10000", "max_luminance": "10000000/10000" },
+                { "side_data_type": "Content light level metadata", "max_content": 1000, "max_average": 400 },
+                { "side_data_type": "DOVI configuration record", "dv_version_major": 1, "dv_version_minor": 0,
+                  "dv_profile": 8, "dv_level": 6, "rpu_present_flag": 1, "el_present_flag": 0, "bl_present_flag": 1,
+                  "dv_bl_signal_compatibility_id": 1 }
+            ] } ], "format": { "duration": "10.0" } }"#;
+        let probe = parse_probe_output(&PathBuf::from(""), serde_json::from_str(json).unwrap()).unwrap();
+        assert_eq!(probe.hdr_format(), Some(HdrFormat::Hdr10));
+        let video = probe.video().unwrap();
+        assert_eq!(video.mastering_display, Some(MasteringDisplay {
+            red: (0.68, 0.32),
+            green: (0.265, 0.69),
+            blue: (0.15, 0.06),
+            white_point: (0.3127, 0.329),
+            min_luminance: 0.005,
+            max_luminance: 1000.0,
+        }));
+        assert_eq!(video.content_light_level, Some(ContentLightLevel { max_content: 1000, max_average: 400 }));
+        assert_eq!(video.dolby_vision, Some(DolbyVision {
+            profile: 8,
+            level: 6,
+            rpu_present: true,
+            el_present: false,
+            bl_present: true,
+            bl_compatibility_id: 1,
+        }));
+    }
+
+    #[test]
+    fn test_parse_pix_fmt() {
+        assert_eq!(parse_pix_fmt("yuv420p"), Some((8, ChromaSubsampling::Yuv420)));
+        assert_eq!(parse_pix_fmt("yuvj422p"), Some((8, ChromaSubsampling::Yuv422)));
+        assert_eq!(parse_pix_fmt("yuv420p10le"), Some((10, ChromaSubsampling::Yuv420)));
+        assert_eq!(parse_pix_fmt("yuv444p12be"), Some((12, ChromaSubsampling::Yuv444)));
+        assert_eq!(parse_pix_fmt("p010le"), Some((10, ChromaSubsampling::Yuv420)));
+        assert_eq!(parse_pix_fmt("gray10le"), Some((10, ChromaSubsampling::Mono)));
+        assert_eq!(parse_pix_fmt("gbrp"), Some((8, ChromaSubsampling::Other)));
+        assert_eq!(parse_pix_fmt("yuv411p"), Some((8, ChromaSubsampling::Other)));
+        assert_eq!(parse_pix_fmt("pal8"), None);
+    }
+
+    #[test]
+    fn test_classify_ffprobe_stderr() {
+        assert_eq!(classify_ffprobe_stderr("[mov,mp4,m4a,3gp,3g2,mj2 @ 0x5581] moov atom not found"), Some(InputParseReason::Truncated));
+        assert_eq!(classify_ffprobe_stderr("x.mkv: Invalid data found when processing input"), Some(InputParseReason::Unreadable));
+        assert_eq!(classify_ffprobe_stderr(""), None);
+    }
+
     fn ffprobe_json_stream_from_frame_rate(frame_rate: &str) -> FFProbeJsonStream {
         FFProbeJsonStream {
-            codec_name: String::new(),
-            codec_tag_string: String::new(),
-            width: 0,
-            height: 0,
-            pix_fmt: String::new(),
-            nb_read_packets: String::new(),
-            avg_frame_rate: String::from(frame_rate),
-            field_order: None,
+            avg_frame_rate: Some(String::from(frame_rate)),
+            ..Default::default()
         }
     }
 }
@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use serde_json;
+
+use crate::error::InputParseError;
+use crate::ffmpeg::probe::{probe_file, AVProbeMetadata, FrameCountSource};
+use crate::fstools::{cache_dir, remove_if_exists, write_atomically, FileFingerprint};
+
+/// Bump whenever `AVProbeMetadata` changes shape so stale caches are dropped.
+const CACHE_VERSION: u32 = 4;
+/// New entries to hold before rewriting the cache mid-walk, so a crash
+/// loses little without rewriting the whole file after every probe.
+const SAVE_EVERY: usize = 100;
+
+#[derive(Serialize, Deserialize)]
+struct ProbeCacheEntry {
+    fingerprint: FileFingerprint,
+    probe: AVProbeMetadata,
+}
+
+#[derive(Deserialize)]
+struct ProbeCacheFile {
+    version: u32,
+    entries: HashMap<String, ProbeCacheEntry>,
+}
+
+#[derive(Serialize)]
+struct ProbeCacheFileRef<'a> {
+    version: u32,
+    entries: &'a HashMap<String, ProbeCacheEntry>,
+}
+
+/// Remembers ffprobe results between runs, keyed by canonical path and
+/// invalidated whenever the file's size, mtime or (optionally) partial
+/// content hash changes. New entries are saved every `SAVE_EVERY` probes
+/// and on `flush`.
+pub struct ProbeCache {
+    path: Option<PathBuf>,
+    hash_content: bool,
+    entries: HashMap<String, ProbeCacheEntry>,
+    unsaved: usize,
+}
+
+impl ProbeCache {
+    pub fn default_path() -> Option<PathBuf> {
+        cache_dir().map(|dir| dir.join("probe-cache.json"))
+    }
+
+    /// A cache that only lives as long as this process.
+    pub fn in_memory() -> Self {
+        ProbeCache {
+            path: None,
+            hash_content: false,
+            entries: HashMap::new(),
+            unsaved: 0,
+        }
+    }
+
+    pub fn open(path: &PathBuf, hash_content: bool) -> Self {
+        let entries = match fs::read_to_string(path) {
+            Ok(json) => match serde_json::from_str::<ProbeCacheFile>(&json) {
+                Ok(cache) if cache.version == CACHE_VERSION => cache.entries,
+                Ok(_) => {
+                    println!("Probe cache {:?} is from another version; starting over.", path);
+                    HashMap::new()
+                },
+                Err(err) => {
+                    println!("Unable to read probe cache {:?}; starting over. {}", path, err);
+                    HashMap::new()
+                },
+            },
+            Err(_) => HashMap::new(),
+        };
+        ProbeCache {
+            path: Some(path.clone()),
+            hash_content,
+            entries,
+            unsaved: 0,
+        }
+    }
+
+    pub fn purge(path: &Path) -> io::Result<()> {
+        remove_if_exists(path)
+    }
+
+    /// Return the cached probe for `input` if it is still valid, otherwise
+    /// probe the file and remember the result.
+    pub fn probe(&mut self, input: &PathBuf, count_packets: bool) -> Result<AVProbeMetadata, InputParseError> {
+        let fingerprint = FileFingerprint::of(input, self.hash_content);
+        if let Some(probe) = self.cached(input, fingerprint.as_ref(), count_packets) {
+            println!("using cached probe for {:?}", input);
+            return Ok(probe.clone());
+        }
+
+        let probe = probe_file(input, count_packets)?;
+        if let Some(fingerprint) = fingerprint {
+            self.remember(input, fingerprint, probe.clone());
+        }
+        Ok(probe)
+    }
+
+    /// Write out any entries not yet saved.
+    pub fn flush(&mut self) {
+        if self.unsaved == 0 {
+            return;
+        }
+        match self.save() {
+            Ok(()) => self.unsaved = 0,
+            Err(err) => println!("Unable to save probe cache; {}", err),
+        }
+    }
+
+    fn cached(&self, input: &Path, fingerprint: Option<&FileFingerprint>, count_packets: bool) -> Option<&AVProbeMetadata> {
+        let entry = self.entries.get(&key(input))?;
+        match fingerprint == Some(&entry.fingerprint)
+            && (!count_packets || entry.probe.frame_count_source == FrameCountSource::Counted) {
+            true => Some(&entry.probe),
+            false => None,
+        }
+    }
+
+    fn remember(&mut self, input: &Path, fingerprint: FileFingerprint, probe: AVProbeMetadata) {
+        self.entries.insert(key(input), ProbeCacheEntry { fingerprint, probe });
+        self.unsaved += 1;
+        if self.unsaved >= SAVE_EVERY {
+            self.flush();
+        }
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let json = serde_json::to_string(&ProbeCacheFileRef {
+            version: CACHE_VERSION,
+            entries: &self.entries,
+        })?;
+        write_atomically(path, json.as_bytes())
+    }
+}
+
+fn key(input: &Path) -> String {
+    fs::canonicalize(input)
+        .unwrap_or_else(|_| input.to_path_buf())
+        .to_string_lossy()
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::time::{Duration, SystemTime};
+    use crate::fstools::ScratchDir;
+
+    fn remember_movie(cache: &mut ProbeCache, input: &Path) {
+        let fingerprint = FileFingerprint::of(input, false).unwrap();
+        cache.remember(input, fingerprint, AVProbeMetadata::empty());
+    }
+
+    fn is_cached(cache: &ProbeCache, input: &Path) -> bool {
+        cache.cached(input, FileFingerprint::of(input, false).as_ref(), false).is_some()
+    }
+
+    #[test]
+    fn test_invalidation() {
+        let dir = ScratchDir::new("probe-cache");
+        let input = dir.join("movie.mkv");
+        fs::write(&input, "original").unwrap();
+        let mut cache = ProbeCache::in_memory();
+        remember_movie(&mut cache, &input);
+        assert!(is_cached(&cache, &input));
+        // only a counted probe will do when asked to count
+        assert!(cache.cached(&input, FileFingerprint::of(&input, false).as_ref(), true).is_none());
+
+        fs::write(&input, "a longer original").unwrap();
+        assert!(!is_cached(&cache, &input));
+
+        remember_movie(&mut cache, &input);
+        File::options().write(true).open(&input).unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(60)).unwrap();
+        assert!(!is_cached(&cache, &input));
+    }
+
+    #[test]
+    fn test_flush_and_reopen() {
+        let dir = ScratchDir::new("probe-cache-reopen");
+        let path = dir.join("probe-cache.json");
+        let input = dir.join("movie.mkv");
+        fs::write(&input, "original").unwrap();
+
+        let mut cache = ProbeCache::open(&path, false);
+        remember_movie(&mut cache, &input);
+        assert!(!path.exists());
+        cache.flush();
+        assert!(is_cached(&ProbeCache::open(&path, false), &input));
+
+        ProbeCache::purge(&path).unwrap();
+        assert!(!path.exists());
+        assert!(!is_cached(&ProbeCache::open(&path, false), &input));
+        // there's nothing to purge the second time
+        ProbeCache::purge(&path).unwrap();
+    }
+
+    #[test]
+    fn test_corrupt_cache() {
+        let dir = ScratchDir::new("probe-cache-corrupt");
+        let path = dir.join("probe-cache.json");
+        fs::write(&path, "{\"version\": 4, \"entries\": [").unwrap();
+        let mut cache = ProbeCache::open(&path, false);
+        assert!(cache.entries.is_empty());
+
+        // and is replaced by the next save
+        let input = dir.join("movie.mkv");
+        fs::write(&input, "original").unwrap();
+        remember_movie(&mut cache, &input);
+        cache.flush();
+        assert!(is_cached(&ProbeCache::open(&path, false), &input));
+    }
+}
@@ -0,0 +1,223 @@
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QualityMetric {
+    Vmaf,
+    Ssim,
+    Psnr,
+}
+
+impl QualityMetric {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "vmaf" => Some(QualityMetric::Vmaf),
+            "ssim" => Some(QualityMetric::Ssim),
+            "psnr" => Some(QualityMetric::Psnr),
+            _ => None,
+        }
+    }
+
+    fn filter(&self) -> &'static str {
+        match self {
+            QualityMetric::Vmaf => "libvmaf",
+            QualityMetric::Ssim => "ssim",
+            QualityMetric::Psnr => "psnr",
+        }
+    }
+}
+
+impl Display for QualityMetric {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QualityMetric::Vmaf => write!(f, "VMAF"),
+            QualityMetric::Ssim => write!(f, "SSIM"),
+            QualityMetric::Psnr => write!(f, "PSNR"),
+        }
+    }
+}
+
+/// A score to aim for, written `metric:score` on the command line, e.g.
+/// `vmaf:95` or `ssim:0.98`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QualityTarget {
+    pub metric: QualityMetric,
+    pub score: f64,
+}
+
+impl QualityTarget {
+    pub fn parse(target: &str) -> Option<Self> {
+        let (metric, score) = target.split_once(':')?;
+        Some(QualityTarget {
+            metric: QualityMetric::parse(metric)?,
+            score: score.parse().ok()?,
+        })
+    }
+}
+
+impl Display for QualityTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.metric, self.score)
+    }
+}
+
+/// Whether this ffmpeg build was configured with `--enable-libvmaf`.
+pub fn has_libvmaf() -> bool {
+    match Command::new("ffmpeg").args(["-hide_banner", "-filters"]).output() {
+        Ok(output) => String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .any(|line| line.split_whitespace().nth(1) == Some("libvmaf")),
+        Err(_) => false,
+    }
+}
+
+/// Scores from a full-length comparison of an output against its input.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct QualityScores {
+    pub psnr: Option<f64>,
+    pub ssim: Option<f64>,
+    pub vmaf: Option<f64>,
+}
+
+impl Display for QualityScores {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let scores: Vec<String> = [
+            (QualityMetric::Vmaf, self.vmaf, 2),
+            (QualityMetric::Ssim, self.ssim, 4),
+            (QualityMetric::Psnr, self.psnr, 2),
+        ].iter()
+            .filter_map(|(metric, score, precision)| score.map(|score| format!("{} {:.*}", metric, precision, score)))
+            .collect();
+        write!(f, "{}", scores.join(" "))
+    }
+}
+
+/// Score a finished output against its input with every metric this ffmpeg
+/// build supports, in a single decode of both files.
+pub fn measure_scores(reference: &Path, distorted: &Path, size: (u64, u64), reference_filters: &[String]) -> Result<QualityScores, String> {
+    let mut metrics = vec![QualityMetric::Psnr, QualityMetric::Ssim];
+    if has_libvmaf() {
+        metrics.push(QualityMetric::Vmaf);
+    }
+    let scores = measure(reference, distorted, &metrics, size, None, reference_filters)?;
+    let score = |metric| metrics.iter().position(|m| *m == metric).map(|i| scores[i]);
+    Ok(QualityScores {
+        psnr: score(QualityMetric::Psnr),
+        ssim: score(QualityMetric::Ssim),
+        vmaf: score(QualityMetric::Vmaf),
+    })
+}
+
+/// Score `distorted` against `reference`, returning one score per metric in
+/// the order given. The distorted video is scaled to `size` (the reference's
+/// dimensions) first; `window` restricts the reference to `(start, duration)`
+/// seconds for comparing against a clip, and `reference_filters` (e.g. the
+/// encode's deinterlacer) make the reference match what was encoded.
+pub fn measure(
+    reference: &Path,
+    distorted: &Path,
+    metrics: &[QualityMetric],
+    size: (u64, u64),
+    window: Option<(f64, f64)>,
+    reference_filters: &[String]) -> Result<Vec<f64>, String> {
+
+    let mut args = vec![
+        PathBuf::from("-hide_banner"),
+        PathBuf::from("-nostats"),
+        PathBuf::from("-i"), distorted.to_path_buf(),
+    ];
+    if let Some((start, duration)) = window {
+        args.extend([
+            PathBuf::from("-ss"), PathBuf::from(format!("{:.3}", start)),
+            PathBuf::from("-t"), PathBuf::from(format!("{:.3}", duration)),
+        ]);
+    }
+    args.extend([
+        PathBuf::from("-i"), reference.to_path_buf(),
+        PathBuf::from("-lavfi"), PathBuf::from(comparison_filter(metrics, size, reference_filters)),
+        PathBuf::from("-f"), PathBuf::from("null"),
+        PathBuf::from("-"),
+    ]);
+
+    let output = Command::new("ffmpeg")
+        .args(args)
+        .output()
+        .map_err(|err| format!("unable to run ffmpeg: {}", err))?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let mut scores = Vec::new();
+    for metric in metrics {
+        match (output.status.success(), parse_score(*metric, &stderr)) {
+            (true, Some(score)) => scores.push(score),
+            _ => return Err(format!("unable to measure {}: {}", metric, stderr.lines().last().unwrap_or_default())),
+        }
+    }
+    Ok(scores)
+}
+
+/// Both inputs are normalised once and split so every metric sees the same frames.
+fn comparison_filter(metrics: &[QualityMetric], size: (u64, u64), reference_filters: &[String]) -> String {
+    let labels = |prefix: &str| (0..metrics.len()).map(|i| format!("[{}{}]", prefix, i)).collect::<String>();
+    let reference_filters: String = reference_filters.iter().map(|filter| format!("{},", filter)).collect();
+    let mut filter = format!(
+        "[0:v]scale={}:{}:flags=bicubic,format=yuv420p10le,setpts=PTS-STARTPTS,split={}{};\
+         [1:v]{}format=yuv420p10le,setpts=PTS-STARTPTS,split={}{}",
+        size.0, size.1, metrics.len(), labels("dist"), reference_filters, metrics.len(), labels("ref"));
+    for (i, metric) in metrics.iter().enumerate() {
+        filter.push_str(&format!(";[dist{}][ref{}]{}", i, i, metric.filter()));
+    }
+    filter
+}
+
+fn parse_score(metric: QualityMetric, stderr: &str) -> Option<f64> {
+    let (line_marker, value_marker) = match metric {
+        QualityMetric::Vmaf => ("VMAF score", "VMAF score: "),
+        QualityMetric::Ssim => ("] SSIM ", "All:"),
+        QualityMetric::Psnr => ("] PSNR ", "average:"),
+    };
+    let line = stderr.lines().rev().find(|line| line.contains(line_marker))?;
+    let value = &line[line.find(value_marker)? + value_marker.len()..];
+    value.split_whitespace().next()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_score() {
+        assert_eq!(parse_score(QualityMetric::Vmaf, "[Parsed_libvmaf_4 @ 0x55d] VMAF score: 95.123456\n"), Some(95.123456));
+        assert_eq!(parse_score(QualityMetric::Ssim,
+            "[Parsed_ssim_4 @ 0x5603] SSIM Y:0.987 (18.86) U:0.991 (20.45) V:0.990 (20.17) All:0.988713 (19.47)\n"), Some(0.988713));
+        assert_eq!(parse_score(QualityMetric::Psnr,
+            "[Parsed_psnr_4 @ 0x5603] PSNR y:41.78 u:45.60 v:45.12 average:42.81 min:38.07 max:49.12\n"), Some(42.81));
+        assert_eq!(parse_score(QualityMetric::Vmaf, "nothing here"), None);
+    }
+
+    #[test]
+    fn test_comparison_filter() {
+        assert_eq!(comparison_filter(&[QualityMetric::Psnr, QualityMetric::Ssim], (1920, 1080), &[]),
+            "[0:v]scale=1920:1080:flags=bicubic,format=yuv420p10le,setpts=PTS-STARTPTS,split=2[dist0][dist1];\
+             [1:v]format=yuv420p10le,setpts=PTS-STARTPTS,split=2[ref0][ref1];\
+             [dist0][ref0]psnr;[dist1][ref1]ssim");
+        assert_eq!(comparison_filter(&[QualityMetric::Vmaf], (720, 480), &[String::from("bwdif")]),
+            "[0:v]scale=720:480:flags=bicubic,format=yuv420p10le,setpts=PTS-STARTPTS,split=1[dist0];\
+             [1:v]bwdif,format=yuv420p10le,setpts=PTS-STARTPTS,split=1[ref0];\
+             [dist0][ref0]libvmaf");
+    }
+
+    #[test]
+    fn test_quality_scores_display() {
+        let scores = QualityScores { psnr: Some(42.123), ssim: Some(0.98765), vmaf: None };
+        assert_eq!(scores.to_string(), "SSIM 0.9877 PSNR 42.12");
+    }
+
+    #[test]
+    fn test_quality_target_parse() {
+        assert_eq!(QualityTarget::parse("vmaf:95"), Some(QualityTarget { metric: QualityMetric::Vmaf, score: 95.0 }));
+        assert_eq!(QualityTarget::parse("SSIM:0.98"), Some(QualityTarget { metric: QualityMetric::Ssim, score: 0.98 }));
+        assert_eq!(QualityTarget::parse("vmaf"), None);
+        assert_eq!(QualityTarget::parse("bogus:1"), None);
+    }
+}
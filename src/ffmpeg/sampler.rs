@@ -0,0 +1,161 @@
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::rc::Rc;
+use human_repr::HumanCount;
+use crate::error::CompressorError;
+use crate::ffmpeg::compressor::CompressorOptions;
+use crate::ffmpeg::describe_command;
+use crate::ffmpeg::probe::{probe_file, AVProbeMetadata};
+use crate::ffmpeg::probe_cache::ProbeCache;
+use super::parameter_factories::{merge_arguments, ParameterFactory};
+
+/// Encodes a handful of short clips spread across the input with the same
+/// parameters a full transcode would use, so quality and savings can be
+/// judged before committing to a multi-hour encode.
+pub struct FFmpegSampler {
+    probe_cache: Rc<RefCell<ProbeCache>>,
+    options: CompressorOptions,
+}
+
+impl FFmpegSampler {
+    pub fn new(options: CompressorOptions, probe_cache: Rc<RefCell<ProbeCache>>) -> Self {
+        FFmpegSampler {
+            probe_cache,
+            options,
+        }
+    }
+
+    pub fn sample(&self, input: &PathBuf, output: &Path, parameters: &dyn ParameterFactory) -> Result<(), CompressorError> {
+        let probe = self.probe_cache.borrow_mut().probe(input, self.options.count_frames);
+        let probe = match probe {
+            Ok(probe) => probe,
+            Err(err) => {
+                println!("{}.\nSkipping {:?}.", err, input);
+                return Ok(());
+            },
+        };
+        if probe.video_codec == self.options.codec {
+            println!("{:?} is already encoded with {}; skipping", input, self.options.codec);
+            return Ok(());
+        }
+        let Some(duration) = probe.duration() else {
+            return Err(CompressorError::for_file(input, "unable to determine duration for sampling."));
+        };
+        let original_bit_rate = bit_rate(input, &probe);
+
+        let length = self.options.sample_length as f64;
+        let mut ratios = Vec::new();
+        for (i, start) in sample_points(duration, self.options.sample_count, length).iter().enumerate() {
+            let sample = sample_filename(output, i + 1);
+            let mut args = vec![
+                PathBuf::from("-ss"), PathBuf::from(format!("{:.3}", start)),
+                PathBuf::from("-i"), PathBuf::from(input),
+                PathBuf::from("-t"), PathBuf::from(format!("{:.3}", length)),
+            ];
+            let mut output_args = parameters.parameters(input, &probe);
+            output_args.extend([
+                PathBuf::from("-c:a"), PathBuf::from("copy"),
+                PathBuf::from("-c:s"), PathBuf::from("copy"),
+                PathBuf::from("-map"), PathBuf::from("0"),
+            ]);
+            args.extend(merge_arguments(output_args, &self.options.overrides.ffmpeg_args));
+            args.push(sample.clone());
+            println!("{}", describe_command(&args));
+            if self.options.dry_run {
+                continue;
+            }
+
+            args.splice(0..0, [
+                PathBuf::from("-hide_banner"),
+                PathBuf::from("-nostats"),
+                PathBuf::from("-loglevel"), PathBuf::from("warning"),
+                PathBuf::from("-y"),
+            ]);
+            match Command::new("ffmpeg").args(args).status() {
+                Ok(status) if status.success() => {},
+                _ => return Err(CompressorError::for_file(input, &format!("unable to encode sample {:?}.", sample))),
+            }
+
+            match probe_file(&sample, false) {
+                Ok(sample_probe) => {
+                    let sample_bit_rate = bit_rate(&sample, &sample_probe);
+                    println!("sample {} @ {:.0}s: {} vs original {} ({:.1}%)",
+                        i + 1,
+                        start,
+                        sample_bit_rate.human_count("b/s"),
+                        original_bit_rate.human_count("b/s"),
+                        100.0 * sample_bit_rate as f64 / original_bit_rate as f64);
+                    ratios.push(sample_bit_rate as f64 / original_bit_rate as f64);
+                },
+                Err(err) => println!("Unable to probe sample {:?}; {}", sample, err),
+            }
+        }
+
+        if self.options.dry_run {
+            println!("dry-run mode; skipping sample transcode operations");
+        } else if !ratios.is_empty() {
+            let ratio = ratios.iter().sum::<f64>() / ratios.len() as f64;
+            let input_size = probe.format.size.unwrap_or(0);
+            // samples are named after the output, so they sit beside it
+            let sample_dir = output.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+            println!("predicted size {} of {} ({:.1}%); samples written to {:?}",
+                ((input_size as f64) * ratio).human_count_bytes(),
+                input_size.human_count_bytes(),
+                100.0 * ratio,
+                sample_dir);
+        }
+        Ok(())
+    }
+}
+
+/// Overall bit rate in bits/s, falling back to size over duration for
+/// containers that don't report one.
+fn bit_rate(path: &PathBuf, probe: &AVProbeMetadata) -> u64 {
+    match (probe.format.bit_rate, probe.format.size, probe.duration()) {
+        (Some(bit_rate), _, _) => bit_rate,
+        (None, Some(size), Some(duration)) if duration > 0.0 => (size as f64 * 8.0 / duration) as u64,
+        _ => {
+            println!("unable to determine bit rate of {:?}", path);
+            1
+        },
+    }
+}
+
+/// Start times of `count` clips of `length` seconds, evenly spread across
+/// the runtime and kept clear of the very beginning and end where intros and
+/// credits would skew the result.
+pub fn sample_points(duration: f64, count: usize, length: f64) -> Vec<f64> {
+    if duration <= length || count == 0 {
+        return vec![0.0];
+    }
+    (1..=count)
+        .map(|i| duration * i as f64 / (count + 1) as f64 - length / 2.0)
+        .map(|start| start.clamp(0.0, duration - length))
+        .collect()
+}
+
+fn sample_filename(output: &Path, index: usize) -> PathBuf {
+    let stem = output.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+    match output.extension() {
+        Some(extension) => output.with_file_name(format!("{}.sample{}.{}", stem, index, extension.to_string_lossy())),
+        None => output.with_file_name(format!("{}.sample{}", stem, index)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_points() {
+        assert_eq!(sample_points(400.0, 3, 20.0), vec![90.0, 190.0, 290.0]);
+        assert_eq!(sample_points(30.0, 3, 20.0), vec![0.0, 5.0, 10.0]);
+        assert_eq!(sample_points(10.0, 3, 20.0), vec![0.0]);
+    }
+
+    #[test]
+    fn test_sample_filename() {
+        assert_eq!(sample_filename(Path::new("/a/movie.av1.mkv"), 2), PathBuf::from("/a/movie.av1.sample2.mkv"));
+    }
+}
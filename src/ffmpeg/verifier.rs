@@ -0,0 +1,195 @@
+use std::path::PathBuf;
+use std::process::Command;
+use crate::error::CompressorError;
+use crate::ffmpeg::probe::{probe_file, AVProbeMetadata, FrameCountSource};
+
+/// Which of the input's streams the output should carry, as changed by
+/// --ffmpeg-args. Our own `-map 0` keeps them all.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct StreamSelection {
+    /// The user's `-map`s replace ours, so any subset may remain.
+    pub mapped: bool,
+    pub no_audio: bool,
+    pub no_subtitles: bool,
+}
+
+impl StreamSelection {
+    pub fn from_args(args: &[String]) -> Self {
+        let given = |option: &str| args.iter().any(|arg| arg == option);
+        StreamSelection {
+            mapped: given("-map"),
+            no_audio: given("-an"),
+            no_subtitles: given("-sn"),
+        }
+    }
+
+    /// A failure when `actual` streams of `kind` can't be what was asked
+    /// for out of the input's `expected`.
+    fn check(&self, kind: &str, dropped: bool, expected: usize, actual: usize) -> Option<String> {
+        match (dropped, self.mapped) {
+            (true, _) if actual > 0 => Some(format!("{} {} streams despite dropping them", actual, kind)),
+            (true, _) => None,
+            (false, true) if actual > expected => Some(format!("{} {} streams but the original has only {}", actual, kind, expected)),
+            (false, true) => None,
+            (false, false) if actual != expected => Some(format!("{} {} streams instead of {}", actual, kind, expected)),
+            (false, false) => None,
+        }
+    }
+}
+
+/// Checks a finished transcode against the probe of its input before we
+/// trust it enough to keep it.
+pub struct FFmpegVerifier {
+    codec: String,
+    decode: bool,
+    duration_tolerance: f64,
+    selection: StreamSelection,
+}
+
+impl FFmpegVerifier {
+    pub fn new(codec: &str, decode: bool, duration_tolerance: f64, selection: StreamSelection) -> Self {
+        FFmpegVerifier {
+            codec: String::from(codec),
+            decode,
+            duration_tolerance,
+            selection,
+        }
+    }
+
+    /// `frame_rate_factor` is how many output frames each input frame
+    /// became, e.g. 2 when deinterlacing to the field rate.
+    pub fn verify(&self, input: &PathBuf, input_probe: &AVProbeMetadata, output: &PathBuf, frame_rate_factor: usize) -> Result<(), CompressorError> {
+        println!("verifying {:?}", output);
+        let output_probe = probe_file(output, false)
+            .map_err(|err| CompressorError::for_file(input, &format!("output failed verification; {}", err)))?;
+
+        let mut failures = compare_probes(input_probe, &output_probe, &self.codec, self.duration_tolerance, self.selection);
+        if self.decode && failures.is_empty() {
+            match decode(output) {
+                Ok((frames, errors)) => {
+                    if !errors.is_empty() {
+                        failures.push(format!("decoding reported errors: {}", errors.join(" | ")));
+                    }
+                    let expected = input_probe.total_frames * frame_rate_factor;
+                    let allowed = allowed_frame_difference(input_probe, self.duration_tolerance) * frame_rate_factor;
+                    if frames.abs_diff(expected) > allowed {
+                        failures.push(format!("decoded {} frames but expected {} (±{})", frames, expected, allowed));
+                    }
+                },
+                Err(err) => failures.push(err),
+            }
+        }
+
+        match failures.is_empty() {
+            true => Ok(()),
+            false => Err(CompressorError::for_file(input, &format!("output failed verification; {}", failures.join("; ")))),
+        }
+    }
+}
+
+fn compare_probes(input: &AVProbeMetadata, output: &AVProbeMetadata, codec: &str, duration_tolerance: f64, selection: StreamSelection) -> Vec<String> {
+    let mut failures = Vec::new();
+    if output.video_codec != codec {
+        failures.push(format!("output is encoded with {}, not {}", output.video_codec, codec));
+    }
+    match (input.duration(), output.duration()) {
+        (Some(expected), Some(actual)) if (expected - actual).abs() > duration_tolerance => {
+            failures.push(format!("duration {:.2}s differs from the original {:.2}s", actual, expected));
+        },
+        (Some(_), None) => failures.push(String::from("output has no duration")),
+        _ => {},
+    }
+    failures.extend(selection.check("audio", selection.no_audio, input.audio_streams().count(), output.audio_streams().count()));
+    failures.extend(selection.check("subtitle", selection.no_subtitles, input.subtitle_streams().count(), output.subtitle_streams().count()));
+    failures
+}
+
+/// An exact packet count should match almost exactly; anything derived from
+/// the container gets the same slack as the duration check.
+fn allowed_frame_difference(probe: &AVProbeMetadata, duration_tolerance: f64) -> usize {
+    match probe.frame_count_source {
+        FrameCountSource::Counted => (probe.total_frames / 1000).max(2),
+        _ => ((duration_tolerance * probe.exact_frame_rate()) as usize).max(probe.total_frames / 100),
+    }
+}
+
+/// Decode the primary video and all audio, returning the number of video
+/// frames decoded and any errors ffmpeg complained about.
+fn decode(path: &PathBuf) -> Result<(usize, Vec<String>), String> {
+    let output = Command::new("ffmpeg")
+        .args([
+            &PathBuf::from("-hide_banner"),
+            &PathBuf::from("-nostats"),
+            &PathBuf::from("-loglevel"), &PathBuf::from("error"),
+            &PathBuf::from("-progress"), &PathBuf::from("pipe:1"),
+            &PathBuf::from("-i"), path,
+            &PathBuf::from("-map"), &PathBuf::from("0:v:0"),
+            &PathBuf::from("-map"), &PathBuf::from("0:a?"),
+            &PathBuf::from("-f"), &PathBuf::from("null"),
+            &PathBuf::from("-"),
+        ])
+        .output()
+        .map_err(|err| format!("unable to run ffmpeg decode pass: {}", err))?;
+
+    let frames = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .rev()
+        .filter_map(|line| line.strip_prefix("frame="))
+        .find_map(|frame| frame.trim().parse().ok())
+        .unwrap_or(0);
+    let errors: Vec<String> = String::from_utf8_lossy(&output.stderr)
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .take(5)
+        .map(String::from)
+        .collect();
+    if !output.status.success() && errors.is_empty() {
+        return Err(String::from("decode pass did not exit successfully"));
+    }
+    Ok((frames, errors))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffmpeg::probe::{AVStream, AVStreamKind, AVAudioStream};
+
+    #[test]
+    fn test_compare_probes() {
+        let mut input = AVProbeMetadata::empty();
+        input.video_codec = String::from("h264");
+        input.format.duration = Some(100.0);
+        input.streams.push(audio_stream());
+        let mut output = input.clone();
+        output.video_codec = String::from("av1");
+        assert!(compare_probes(&input, &output, "av1", 1.0, StreamSelection::default()).is_empty());
+
+        output.format.duration = Some(90.0);
+        output.streams.clear();
+        let failures = compare_probes(&input, &output, "hevc", 1.0, StreamSelection::default());
+        assert_eq!(failures.len(), 3);
+    }
+
+    #[test]
+    fn test_compare_probes_with_selection() {
+        let mut input = AVProbeMetadata::empty();
+        input.video_codec = String::from("h264");
+        input.streams.extend([audio_stream(), audio_stream()]);
+        let mut output = input.clone();
+        output.video_codec = String::from("av1");
+        output.streams.pop();
+
+        let args = |args: &str| args.split_whitespace().map(String::from).collect::<Vec<String>>();
+        assert_eq!(compare_probes(&input, &output, "av1", 1.0, StreamSelection::default()).len(), 1);
+        assert!(compare_probes(&input, &output, "av1", 1.0, StreamSelection::from_args(&args("-map 0:v -map 0:a:0"))).is_empty());
+        assert_eq!(compare_probes(&input, &output, "av1", 1.0, StreamSelection::from_args(&args("-an"))).len(), 1);
+        output.streams.clear();
+        assert!(compare_probes(&input, &output, "av1", 1.0, StreamSelection::from_args(&args("-an -g 48"))).is_empty());
+        output.streams.extend([audio_stream(), audio_stream(), audio_stream()]);
+        assert_eq!(compare_probes(&input, &output, "av1", 1.0, StreamSelection::from_args(&args("-map 0"))).len(), 1);
+    }
+
+    fn audio_stream() -> AVStream {
+        AVStream::for_test(1, "aac", AVStreamKind::Audio(AVAudioStream::default()))
+    }
+}
@@ -1,30 +1,43 @@
-use std::fs::read_dir;
-use std::path::PathBuf;
+use std::fs::{self, read_dir};
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
-use crate::compressor::Compressor;
-use crate::fstools::{classify_file, DirEntryCategory};
+use crate::compressor::{Compressor, OutputLocation};
+use crate::fstools::{classify_file, is_partial, DirEntryCategory};
 use crate::error::FilePathHandlerError;
 
 #[derive(Clone, Debug)]
 pub struct FilePathHandlerOptions {
     pub recursive: bool,
+    pub output: Option<PathBuf>,
+    pub output_dir: Option<PathBuf>,
 }
 
 pub struct FilePathHandler {
     path: PathBuf,
+    root: PathBuf,
     options: FilePathHandlerOptions,
-    compressor: Rc<Box<Compressor>>,
+    compressor: Rc<Compressor>,
 }
 
 impl FilePathHandler {
-    pub fn for_pathbuf(path: PathBuf, options: FilePathHandlerOptions, compressor: &Rc<Box<Compressor>>) -> Self {
+    pub fn for_pathbuf(path: PathBuf, options: FilePathHandlerOptions, compressor: &Rc<Compressor>) -> Self {
         FilePathHandler {
+            root: path.clone(),
             path,
             options,
             compressor: Rc::clone(compressor),
         }
     }
 
+    fn for_child(&self, path: PathBuf) -> Self {
+        FilePathHandler {
+            path,
+            root: self.root.clone(),
+            options: self.options.clone(),
+            compressor: Rc::clone(&self.compressor),
+        }
+    }
+
     pub fn handle(&self) -> Result<(), FilePathHandlerError> {
         match classify_file(&PathBuf::from(&self.path)) {
             DirEntryCategory::Unknown => {
@@ -44,22 +57,98 @@ impl FilePathHandler {
                 if self.options.recursive {
                     match read_dir(&self.path) {
                         Ok(entries) => {
+                            // keep walking when a single entry fails so one odd file
+                            // doesn't stop the rest of the library from being processed,
+                            // but a stop signal ends the whole walk
+                            let mut failures = 0;
                             for entry in entries.filter_map(|e| e.ok()) {
-                                if let Err(err) = FilePathHandler::for_pathbuf(entry.path(), self.options.clone(), &self.compressor).handle() {
-                                    return Err(err);
+                                let result = self.for_child(entry.path()).handle();
+                                if self.compressor.stopped() {
+                                    return Err(FilePathHandlerError::for_file_path(&self.path, "Stopped before finishing the directory."));
+                                }
+                                if let Err(err) = result {
+                                    println!("{}", err);
+                                    failures += 1;
                                 }
                             }
-                            Ok(())
+                            match failures {
+                                0 => Ok(()),
+                                _ => Err(FilePathHandlerError::for_file_path(&self.path, &format!("{} entries could not be processed.", failures))),
+                            }
                         },
-                        Err(_) => Err(FilePathHandlerError::for_file_path(&self.path, "not implemented")),
+                        Err(err) => Err(FilePathHandlerError::for_file_path(&self.path, &format!("Unable to read directory: {err}"))),
                     }
                 } else {
                     Ok(())
                 }
             },
+            // stale partials are dealt with at startup; never transcode one
+            DirEntryCategory::RegularFile if is_partial(&self.path) => Ok(()),
             DirEntryCategory::RegularFile => self.compressor
-                .compress_file(&PathBuf::from(&self.path), &PathBuf::from(""))
-                .or_else(|e| Err(FilePathHandlerError::for_file_path(&self.path, &format!("Error compressing regular file: {:?}.", e)))),
+                .compress_file(&PathBuf::from(&self.path), &self.output_location(), &self.relative_dir())
+                .map_err(|e| FilePathHandlerError::for_file_path(&self.path, &format!("Error compressing regular file: {:?}.", e))),
         }
     }
+
+    /// With an output directory the input tree is mirrored beneath it,
+    /// starting with the directory that was named on the command line, so
+    /// `--output-dir /staging /share/movies` writes `/staging/movies/...`.
+    fn output_location(&self) -> OutputLocation {
+        match (&self.options.output, &self.options.output_dir) {
+            (Some(file), _) => OutputLocation::File(file.clone()),
+            (None, Some(dir)) => OutputLocation::Directory(dir.join(self.relative_dir())),
+            (None, None) => OutputLocation::BesideInput,
+        }
+    }
+
+    fn relative_dir(&self) -> PathBuf {
+        mirrored_dir(&self.root, &self.path)
+    }
+}
+
+/// The directory of `path`, found while walking `root`, relative to the
+/// parent of `root`, e.g. `movies/Alien (1979)`; empty when `root` is the
+/// file itself. Both are canonicalized first, as `..` or `/` have no useful
+/// lexical parent.
+fn mirrored_dir(root: &Path, path: &Path) -> PathBuf {
+    if root == path {
+        return PathBuf::new();
+    }
+    let canonical = |path: &Path| fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let root = canonical(root);
+    let base = root.parent().unwrap_or(&root);
+    path.parent()
+        .map(canonical)
+        .and_then(|dir| dir.strip_prefix(base).ok().map(PathBuf::from))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use crate::fstools::ScratchDir;
+
+    #[test]
+    fn test_mirrored_dir() {
+        let library = ScratchDir::new("mirror");
+        let movie = library.join("movies").join("Alien").join("alien.mkv");
+        fs::create_dir_all(movie.parent().unwrap()).unwrap();
+        fs::write(&movie, "").unwrap();
+
+        assert_eq!(mirrored_dir(&movie, &movie), PathBuf::new());
+        assert_eq!(mirrored_dir(&library.join("movies"), &movie), PathBuf::from("movies/Alien"));
+        let trailing = PathBuf::from(format!("{}/", library.join("movies").display()));
+        assert_eq!(mirrored_dir(&trailing, &trailing.join("Alien/alien.mkv")), PathBuf::from("movies/Alien"));
+        let dotdot = library.join("movies").join("Alien").join("..");
+        assert_eq!(mirrored_dir(&dotdot, &dotdot.join("Alien/alien.mkv")), PathBuf::from("movies/Alien"));
+        assert_eq!(mirrored_dir(Path::new("/"), &movie), movie.parent().unwrap().canonicalize().unwrap().strip_prefix("/").unwrap());
+
+        // relative roots, as typed at a shell in the crate directory
+        let cwd = env::current_dir().unwrap().canonicalize().unwrap();
+        let cwd_name = PathBuf::from(cwd.file_name().unwrap());
+        assert_eq!(mirrored_dir(Path::new("."), Path::new("./src/main.rs")), cwd_name.join("src"));
+        assert_eq!(mirrored_dir(Path::new(".."), &Path::new("..").join(&cwd_name).join("src/main.rs")),
+            PathBuf::from(cwd.parent().unwrap().file_name().unwrap()).join(&cwd_name).join("src"));
+    }
 }
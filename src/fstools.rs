@@ -1,5 +1,17 @@
-use std::path::PathBuf;
-use std::fs;
+use std::path::{Path, PathBuf};
+use std::env;
+use std::fs::{self, File, FileTimes};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+
+/// Appended to outputs while ffmpeg is still writing them; only a
+/// successful encode is renamed to the final name.
+pub const PARTIAL_EXTENSION: &str = "partial";
+const HASH_CHUNK_SIZE: u64 = 1024 * 1024;
+/// ffmpeg writes to a partial continuously, so one left untouched this long
+/// belongs to a run that died rather than one still going.
+const STALE_PARTIAL_AGE: Duration = Duration::from_secs(15 * 60);
 
 pub enum DirEntryCategory {
     DoesNotExist,
@@ -25,3 +37,243 @@ pub fn classify_file(path: &PathBuf) -> DirEntryCategory {
         Err(_) => DirEntryCategory::DoesNotExist,
     }
 }
+
+/// Where we keep state between runs: `$XDG_CACHE_HOME/compress-mkv`, falling
+/// back to `~/.cache/compress-mkv`.
+pub fn cache_dir() -> Option<PathBuf> {
+    match env::var_os("XDG_CACHE_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => Some(PathBuf::from(dir).join("compress-mkv")),
+        None => env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache").join("compress-mkv")),
+    }
+}
+
+/// Where users configure us: `$XDG_CONFIG_HOME/compress-mkv`, falling back
+/// to `~/.config/compress-mkv`.
+pub fn config_dir() -> Option<PathBuf> {
+    match env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => Some(PathBuf::from(dir).join("compress-mkv")),
+        None => env::var_os("HOME").map(|home| PathBuf::from(home).join(".config").join("compress-mkv")),
+    }
+}
+
+pub fn partial_path(output: &Path) -> PathBuf {
+    let mut name = output.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(PARTIAL_EXTENSION);
+    output.with_file_name(name)
+}
+
+pub fn is_partial(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == PARTIAL_EXTENSION)
+}
+
+/// Whether `path` is a partial we would have written: `<stem>.<codec>.<container>.partial`
+/// for one of `codecs`. Other tools use `.partial` too (rclone, browsers),
+/// and theirs are none of our business.
+pub fn is_own_partial(path: &Path, codecs: &[&str], container: &str) -> bool {
+    let Some(name) = path.file_name().map(|name| name.to_string_lossy()) else {
+        return false;
+    };
+    let Some(output) = name.strip_suffix(&format!(".{}", PARTIAL_EXTENSION)) else {
+        return false;
+    };
+    codecs.iter().any(|codec| output.strip_suffix(&format!(".{}.{}", codec, container))
+        .is_some_and(|stem| !stem.is_empty()))
+}
+
+/// Our partials beneath `dir` that no running encode is still writing.
+pub fn find_stale_partials(dir: &Path, recursive: bool, codecs: &[&str], container: &str) -> Vec<PathBuf> {
+    let mut partials = Vec::new();
+    let Ok(entries) = fs::read_dir(dir) else {
+        return partials;
+    };
+    for path in entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()) {
+        match classify_file(&path) {
+            DirEntryCategory::Directory if recursive => partials.extend(find_stale_partials(&path, recursive, codecs, container)),
+            DirEntryCategory::RegularFile if is_own_partial(&path, codecs, container) && is_stale(&path) => partials.push(path),
+            _ => {},
+        }
+    }
+    partials
+}
+
+fn is_stale(path: &Path) -> bool {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+        .is_some_and(|age| age >= STALE_PARTIAL_AGE)
+}
+
+pub fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+/// Give `to` the permissions, timestamps and (where we're allowed to)
+/// ownership of `from`.
+pub fn copy_file_metadata(from: &Path, to: &Path) -> io::Result<()> {
+    let metadata = fs::metadata(from)?;
+    fs::set_permissions(to, metadata.permissions())?;
+    let mut times = FileTimes::new().set_modified(metadata.modified()?);
+    if let Ok(accessed) = metadata.accessed() {
+        times = times.set_accessed(accessed);
+    }
+    File::options().write(true).open(to)?.set_times(times)?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        // only root may give files away; keep going with our own ownership otherwise
+        if let Err(err) = std::os::unix::fs::chown(to, Some(metadata.uid()), Some(metadata.gid())) {
+            println!("Unable to preserve ownership of {:?}; {}", to, err);
+        }
+    }
+    Ok(())
+}
+
+/// Rename, falling back to copy and delete when `to` is on another
+/// filesystem.
+pub fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(err) if err.kind() == io::ErrorKind::CrossesDevices => {},
+        result => return result,
+    }
+    fs::copy(from, to)?;
+    copy_file_metadata(from, to)?;
+    fs::remove_file(from)
+}
+
+/// Write then rename, so an interrupted write can't leave `path` corrupt.
+pub fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = partial_path(path);
+    fs::write(&tmp, contents)?;
+    fs::rename(&tmp, path)
+}
+
+/// Enough about a file to notice when it has changed since we last saw it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileFingerprint {
+    size: u64,
+    mtime_secs: u64,
+    mtime_nanos: u32,
+    hash: Option<String>,
+}
+
+impl FileFingerprint {
+    pub fn of(path: &Path, hash_content: bool) -> Option<Self> {
+        let metadata = fs::metadata(path).ok()?;
+        let mtime = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        let hash = match hash_content {
+            true => Some(format!("{:016x}", partial_content_hash(path, metadata.len()).ok()?)),
+            false => None,
+        };
+        Some(FileFingerprint {
+            size: metadata.len(),
+            mtime_secs: mtime.as_secs(),
+            mtime_nanos: mtime.subsec_nanos(),
+            hash,
+        })
+    }
+}
+
+/// FNV-1a over the first and last megabyte; cheap enough to run on every
+/// file of a large library while still catching in-place rewrites that
+/// keep size and mtime.
+fn partial_content_hash(path: &Path, size: u64) -> io::Result<u64> {
+    let mut file = File::open(path)?;
+    let mut buf = Vec::new();
+    (&mut file).take(HASH_CHUNK_SIZE).read_to_end(&mut buf)?;
+    if size > 2 * HASH_CHUNK_SIZE {
+        file.seek(SeekFrom::End(-(HASH_CHUNK_SIZE as i64)))?;
+        file.take(HASH_CHUNK_SIZE).read_to_end(&mut buf)?;
+    }
+    Ok(fnv1a(&buf))
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+}
+
+/// A fresh directory under the system temp directory for one test, removed
+/// again when dropped, whether or not the test passed.
+#[cfg(test)]
+pub struct ScratchDir(PathBuf);
+
+#[cfg(test)]
+impl ScratchDir {
+    pub fn new(name: &str) -> Self {
+        let dir = env::temp_dir().join(format!("compress-mkv-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        ScratchDir(dir)
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for ScratchDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partial_path() {
+        let partial = partial_path(Path::new("/a/movie.av1.mkv"));
+        assert_eq!(partial, PathBuf::from("/a/movie.av1.mkv.partial"));
+        assert!(is_partial(&partial));
+        assert!(!is_partial(Path::new("/a/movie.av1.mkv")));
+    }
+
+    #[test]
+    fn test_is_own_partial() {
+        let codecs = ["av1", "hevc"];
+        assert!(is_own_partial(Path::new("/a/movie.av1.mkv.partial"), &codecs, "mkv"));
+        assert!(is_own_partial(Path::new("/a/movie.hevc.mkv.partial"), &codecs, "mkv"));
+        assert!(!is_own_partial(Path::new("/a/movie.av1.mp4.partial"), &codecs, "mkv"));
+        assert!(!is_own_partial(Path::new("/a/movie.mkv.partial"), &codecs, "mkv"));
+        assert!(!is_own_partial(Path::new("/a/.av1.mkv.partial"), &codecs, "mkv"));
+        assert!(!is_own_partial(Path::new("/a/movie.av1.mkv"), &codecs, "mkv"));
+    }
+
+    #[test]
+    fn test_find_stale_partials() {
+        let dir = ScratchDir::new("partials");
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        let old = SystemTime::now() - 2 * STALE_PARTIAL_AGE;
+        for name in ["a.av1.mkv.partial", "sub/b.av1.mkv.partial", "c.mkv.partial", "d.av1.mkv"] {
+            File::create(dir.join(name)).unwrap().set_modified(old).unwrap();
+        }
+        File::create(dir.join("running.av1.mkv.partial")).unwrap();
+
+        assert_eq!(find_stale_partials(&dir, false, &["av1"], "mkv"), vec![dir.join("a.av1.mkv.partial")]);
+        let mut partials = find_stale_partials(&dir, true, &["av1"], "mkv");
+        partials.sort();
+        assert_eq!(partials, vec![dir.join("a.av1.mkv.partial"), dir.join("sub/b.av1.mkv.partial")]);
+    }
+
+    #[test]
+    fn test_fnv1a() {
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(fnv1a(b"foobar"), 0x85944171f73967e8);
+    }
+}
@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use serde_json;
+use crate::ffmpeg::quality::QualityScores;
+use crate::fstools::{cache_dir, write_atomically, FileFingerprint};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Verdict {
+    Compressed,
+    NotWorthCompressing,
+}
+
+/// What we learned about one file: the ledger keeps it, the end-of-run
+/// summary prints it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FileReport {
+    pub codec: String,
+    pub verdict: Verdict,
+    pub input_size: u64,
+    pub output_size: u64,
+    #[serde(default)]
+    pub input_bit_rate: Option<u64>,
+    #[serde(default)]
+    pub output_bit_rate: Option<u64>,
+    #[serde(default)]
+    pub metrics: Option<QualityScores>,
+}
+
+impl FileReport {
+    /// Whether this report says `codec` isn't worth trying again.
+    pub fn rules_out(&self, codec: &str) -> bool {
+        self.verdict == Verdict::NotWorthCompressing && self.codec == codec
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LedgerEntry {
+    fingerprint: FileFingerprint,
+    #[serde(flatten)]
+    pub report: FileReport,
+}
+
+/// What became of each file we've worked on, kept between runs so files
+/// that aren't worth compressing aren't transcoded again every night.
+pub struct Ledger {
+    path: Option<PathBuf>,
+    entries: HashMap<String, LedgerEntry>,
+}
+
+impl Ledger {
+    pub fn default_path() -> Option<PathBuf> {
+        cache_dir().map(|dir| dir.join("ledger.json"))
+    }
+
+    pub fn in_memory() -> Self {
+        Ledger {
+            path: None,
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn open(path: &Path) -> Self {
+        let entries = match fs::read_to_string(path) {
+            Ok(json) => serde_json::from_str(&json).unwrap_or_else(|err| {
+                println!("Unable to read ledger {:?}; starting over. {}", path, err);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+        Ledger {
+            path: Some(path.to_path_buf()),
+            entries,
+        }
+    }
+
+    /// The entry for `input`, unless the file has changed since it was made.
+    pub fn lookup(&self, input: &Path) -> Option<&LedgerEntry> {
+        let entry = self.entries.get(&key(input))?;
+        match FileFingerprint::of(input, false) {
+            Some(fingerprint) if fingerprint == entry.fingerprint => Some(entry),
+            _ => None,
+        }
+    }
+
+    pub fn record(&mut self, input: &Path, report: &FileReport) {
+        let Some(fingerprint) = FileFingerprint::of(input, false) else {
+            return;
+        };
+        self.entries.insert(key(input), LedgerEntry {
+            fingerprint,
+            report: report.clone(),
+        });
+        if let Err(err) = self.save() {
+            println!("Unable to save ledger; {}", err);
+        }
+    }
+
+    fn save(&self) -> io::Result<()> {
+        match &self.path {
+            Some(path) => write_atomically(path, serde_json::to_string(&self.entries)?.as_bytes()),
+            None => Ok(()),
+        }
+    }
+}
+
+fn key(input: &Path) -> String {
+    fs::canonicalize(input)
+        .unwrap_or_else(|_| input.to_path_buf())
+        .to_string_lossy()
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fstools::ScratchDir;
+
+    fn report(codec: &str, verdict: Verdict) -> FileReport {
+        FileReport {
+            codec: String::from(codec),
+            verdict,
+            input_size: 1000,
+            output_size: 990,
+            input_bit_rate: None,
+            output_bit_rate: None,
+            metrics: None,
+        }
+    }
+
+    #[test]
+    fn test_rules_out() {
+        assert!(report("av1", Verdict::NotWorthCompressing).rules_out("av1"));
+        assert!(!report("av1", Verdict::NotWorthCompressing).rules_out("hevc"));
+        assert!(!report("av1", Verdict::Compressed).rules_out("av1"));
+    }
+
+    #[test]
+    fn test_lookup() {
+        let dir = ScratchDir::new("ledger");
+        let input = dir.join("movie.mkv");
+        fs::write(&input, "original").unwrap();
+
+        let mut ledger = Ledger::open(&dir.join("ledger.json"));
+        ledger.record(&input, &report("av1", Verdict::NotWorthCompressing));
+        let reopened = Ledger::open(&dir.join("ledger.json"));
+        assert!(reopened.lookup(&input).is_some_and(|entry| entry.report.rules_out("av1")));
+
+        // a file that has changed since is worth another look
+        fs::write(&input, "a different original").unwrap();
+        assert!(reopened.lookup(&input).is_none());
+    }
+}
@@ -3,6 +3,7 @@ pub mod error;
 pub mod fstools;
 pub mod ffmpeg;
 pub mod file_path_handler;
+pub mod ledger;
 
 use std::cell::RefCell;
 use std::path::PathBuf;
@@ -12,25 +13,304 @@ use std::sync::mpsc;
 use std::thread;
 
 use ffmpeg::compressor::CompressorOptions;
+use ffmpeg::content::ContentMode;
+use ffmpeg::cropdetect::CropMode;
+use ffmpeg::dolby_vision::DolbyVisionPolicy;
+use ffmpeg::grain::FilmGrainMode;
+use ffmpeg::probe_cache::ProbeCache;
+use ffmpeg::parameter_factories::crf_table::CrfTable;
+use ffmpeg::parameter_factories::quality_preset::{QualityPresets, DEFAULT_QUALITY_PRESET, SVTAV1_PRESETS, X265_PRESETS};
+use ffmpeg::parameter_factories::{av1, hevc, parse_encoder_params, EncoderOverrides};
+use ffmpeg::parameter_factories::filters::{Deinterlace, DeinterlaceMode, DeinterlaceRate, Deinterlacer, Scale, Scaler, Tonemap, TonemapAlgorithm};
+use ffmpeg::quality::QualityTarget;
+use ledger::Ledger;
 use file_path_handler::{FilePathHandler, FilePathHandlerOptions};
 use rustop::opts;
-use compressor::Compressor;
+use compressor::{Compressor, SUPPORTED_CODECS};
+use fstools::{find_stale_partials, remove_if_exists};
 use signal_hook::{consts::{SIGINT, SIGHUP, SIGTERM}, iterator::Signals};
 
 fn main() -> ExitCode {
     let (args, _rest) = opts! {
         synopsis "Compress mkv files for use by Jellyfin/Emby/etc";
         version env!("CARGO_PKG_VERSION");
-        opt sample:bool=false, desc:"Transcode a small sample. (not implemented)";
+        opt sample:bool=false, desc:"Transcode a few short samples and compare their bit rate to the original.";
         opt dry_run:bool=false, desc:"Describe what would be done, but don't actually do anything.";
         opt recursive:bool=true, desc:"Do not recurse into subdirectories.";
         opt codec:String=String::from("av1"), desc:"Codec to use for compression. [av1, hevc]";
         opt container:String=String::from("mkv"), desc:"Container";
-        opt fast:bool=false, desc:"Use faster encoding parameters.";
-        opt extreme:bool=false, desc:"Compress with extreme high quality.";
+        opt fast:bool=false, desc:"Deprecated; same as --quality fast.";
+        opt extreme:bool=false, desc:"Deprecated; same as --quality archive.";
+        opt count_frames:bool=false, desc:"Count every frame when probing instead of estimating. (slow)";
+        opt probe_cache:bool=true, desc:"Don't remember probe results between runs.";
+        opt hash_probe_cache:bool=false, desc:"Also key cached probe results on a hash of the file's first and last megabyte.";
+        opt purge_probe_cache:bool=false, desc:"Delete all cached probe results.";
+        opt sample_count:usize=3, desc:"Number of clips to transcode in sample mode.";
+        opt sample_length:u64=20, desc:"Length of each sample clip in seconds.";
+        opt output:Option<String>, desc:"Output file; only valid with a single input file.";
+        opt output_dir:Option<String>, desc:"Write outputs beneath this directory, mirroring the input directory tree.";
+        opt remove_partials:bool=true, desc:"Report partial files left behind by interrupted runs instead of removing them.";
+        opt max_size_ratio:f64=1.0, desc:"Discard transcodes larger than this fraction of the original.";
+        opt abort_after:f64=10.0, desc:"Percent of frames after which a transcode projected to exceed --max-size-ratio is abandoned.";
+        opt target_quality:Option<String>, desc:"Search sample clips for the CRF that reaches this score, e.g. vmaf:95 or ssim:0.98.";
+        opt fallback_quality:String=String::from("ssim:0.98"), desc:"Quality target to use instead when ffmpeg lacks libvmaf.";
+        opt target_ratio:Option<f64>, desc:"Retry at a higher CRF while the transcode is larger than this fraction of the original.";
+        opt max_attempts:u32=3, desc:"Maximum number of transcodes per file with --target-ratio.";
+        opt crf_step:u16=4, desc:"CRF increase per retry with --target-ratio, and between the CRFs --target-quality tries.";
+        opt max_crf:Option<u16>, desc:"Never retry beyond this CRF with --target-ratio.";
+        opt retry_rejected:bool=false, desc:"Transcode files previously found not worth compressing.";
+        opt ledger:bool=true, desc:"Don't remember what became of each file between runs.";
+        opt verify:bool=true, desc:"Skip decoding transcodes to verify them. (not allowed with --replace)";
+        opt verify_tolerance:f64=1.0, desc:"Allowed difference in seconds between original and transcode duration.";
+        opt replace:bool=false, desc:"Replace originals with their verified transcodes. (not allowed with --output or --output-dir)";
+        opt keep_name:bool=false, desc:"When replacing, keep the original filename instead of the codec-tagged one.";
+        opt archive_dir:Option<String>, desc:"When replacing, move originals beneath this directory, mirroring the input directory tree, instead of deleting them.";
+        opt metrics:bool=false, desc:"Measure PSNR, SSIM and VMAF (when available) of kept transcodes against their originals.";
+        opt deinterlace:String=String::from("auto"), desc:"Deinterlace: auto (interlaced sources only), on or off.";
+        opt deinterlacer:String=String::from("bwdif"), desc:"Deinterlacing filter: bwdif or yadif.";
+        opt deinterlace_rate:String=String::from("frame"), desc:"Deinterlace to one frame per frame or one per field: frame or field.";
+        opt crop:String=String::from("auto"), desc:"Crop black bars: auto (detect), off, or W:H:X:Y.";
+        opt dolby_vision:String=String::from("skip"), desc:"Dolby Vision sources: skip, base-layer (drop the RPU) or keep (profile 8 to hevc).";
+        opt tonemap:bool=false, desc:"Tonemap HDR sources to BT.709 SDR.";
+        opt tonemap_algorithm:String=String::from("hable"), desc:"Tonemapping curve: clip, linear, gamma, reinhard, hable or mobius.";
+        opt tonemap_peak:f64=100.0, desc:"Peak luminance of the SDR output in cd/m².";
+        opt max_width:Option<u64>, desc:"Downscale wider sources to this width, keeping the aspect ratio. (default: from --quality)";
+        opt max_height:Option<u64>, desc:"Downscale taller sources to this height, keeping the aspect ratio. (default: from --quality)";
+        opt scaler:String=String::from("lanczos"), desc:"Scaling algorithm: bilinear, bicubic, lanczos, spline or area.";
+        opt film_grain:Option<String>, desc:"AV1 film grain synthesis: auto (measure each title), off, or a level 0-50. (default: from --quality, else auto)";
+        opt film_grain_denoise:bool=false, desc:"Denoise before encoding when synthesising film grain.";
+        opt content:String=String::from("auto"), desc:"Tune for content: auto (from path and genre tag), live-action or animation.";
+        opt crf_table:Option<String>, desc:"CRF by output resolution, e.g. 480:20,720:23,1080:25,1080@50:27,2160:29; --quality adjusts it.";
+        opt quality:String=String::from(DEFAULT_QUALITY_PRESET), desc:"Quality preset: archive, balanced, fast, mobile, or one from the config file.";
+        opt list_quality_presets:bool=false, desc:"List the quality presets and exit.";
+        opt crf:Option<u16>, desc:"Encode at this CRF instead of one from --crf-table and --quality: 1-63 for av1, 0-51 for hevc.";
+        opt preset:Option<String>, desc:"Encoder preset instead of --quality's: -2 to 13 for av1, ultrafast to placebo for hevc.";
+        opt svtav1_params:Option<String>, desc:"Extra libsvtav1 parameters as key=value:key=value, replacing ours for the same keys.";
+        opt x265_params:Option<String>, desc:"Extra libx265 parameters as key=value:key=value, replacing ours for the same keys.";
+        opt ffmpeg_args:Option<String>, desc:"Extra ffmpeg output arguments separated by spaces, e.g. --ffmpeg-args=\"-g 48\", replacing ours for the same options (but not -f).";
         param infiles:Vec<String>, desc:"Input files/directories";
     }.parse_or_exit();
 
+    let quality_presets_path = QualityPresets::default_path();
+    let quality_presets = match &quality_presets_path {
+        Some(path) => match QualityPresets::load(path) {
+            Ok(presets) => presets,
+            Err(err) => {
+                println!("Unable to load quality presets; {}", err);
+                return ExitCode::FAILURE;
+            },
+        },
+        None => QualityPresets::builtin(),
+    };
+    if args.list_quality_presets {
+        for (name, preset) in quality_presets.iter() {
+            println!("{}: {}", name, preset.description);
+            println!("    {}", preset);
+        }
+        if let Some(path) = &quality_presets_path {
+            println!("Define more in {:?}.", path);
+        }
+        return ExitCode::SUCCESS;
+    }
+    let quality_name = match (args.fast, args.extreme) {
+        (false, false) => args.quality.as_str(),
+        (true, false) => "fast",
+        (false, true) => "archive",
+        (true, true) => {
+            println!("--fast and --extreme can't be used together; choose a --quality preset.");
+            return ExitCode::FAILURE;
+        },
+    };
+    if args.fast || args.extreme {
+        println!("--fast and --extreme are deprecated; using --quality {}.", quality_name);
+    }
+    let Some(mut quality) = quality_presets.get(quality_name).cloned() else {
+        println!("Unknown --quality {:?}; expected one of {}.", quality_name, quality_presets.names().join(", "));
+        return ExitCode::FAILURE;
+    };
+
+    let probe_cache_path = ProbeCache::default_path();
+    if args.purge_probe_cache {
+        if let Some(path) = &probe_cache_path {
+            match ProbeCache::purge(path) {
+                Ok(_) => println!("Purged probe cache {:?}.", path),
+                Err(err) => {
+                    println!("Unable to purge probe cache {:?}; {}", path, err);
+                    return ExitCode::FAILURE;
+                },
+            }
+        }
+        if args.infiles.is_empty() {
+            return ExitCode::SUCCESS;
+        }
+    }
+
+    if let Some(output) = &args.output {
+        if args.output_dir.is_some() {
+            println!("--output and --output-dir can't be used together.");
+            return ExitCode::FAILURE;
+        }
+        if args.infiles.len() != 1 || PathBuf::from(&args.infiles[0]).is_dir() {
+            println!("--output {:?} requires exactly one input file.", output);
+            return ExitCode::FAILURE;
+        }
+    }
+
+    if !args.replace && (args.keep_name || args.archive_dir.is_some()) {
+        println!("--keep-name and --archive-dir only apply with --replace.");
+        return ExitCode::FAILURE;
+    }
+
+    if args.replace && (args.output.is_some() || args.output_dir.is_some()) {
+        println!("--replace puts transcodes beside their originals; drop --output and --output-dir.");
+        return ExitCode::FAILURE;
+    }
+
+    if args.replace && !args.verify {
+        println!("--replace requires verification; drop --no-verify.");
+        return ExitCode::FAILURE;
+    }
+
+    let target_quality = match args.target_quality.as_ref().map(|t| (t, QualityTarget::parse(t))) {
+        Some((_, Some(target))) => Some(target),
+        Some((target, None)) => {
+            println!("Unable to parse --target-quality {:?}; expected e.g. vmaf:95.", target);
+            return ExitCode::FAILURE;
+        },
+        None => None,
+    };
+    let Some(fallback_quality) = QualityTarget::parse(&args.fallback_quality) else {
+        println!("Unable to parse --fallback-quality {:?}; expected e.g. ssim:0.98.", args.fallback_quality);
+        return ExitCode::FAILURE;
+    };
+
+    let deinterlace = match (
+        DeinterlaceMode::parse(&args.deinterlace),
+        Deinterlacer::parse(&args.deinterlacer),
+        DeinterlaceRate::parse(&args.deinterlace_rate)) {
+        (Some(mode), Some(deinterlacer), Some(rate)) => Deinterlace { mode, deinterlacer, rate },
+        (None, _, _) => {
+            println!("Unable to parse --deinterlace {:?}; expected auto, on or off.", args.deinterlace);
+            return ExitCode::FAILURE;
+        },
+        (_, None, _) => {
+            println!("Unable to parse --deinterlacer {:?}; expected bwdif or yadif.", args.deinterlacer);
+            return ExitCode::FAILURE;
+        },
+        (_, _, None) => {
+            println!("Unable to parse --deinterlace-rate {:?}; expected frame or field.", args.deinterlace_rate);
+            return ExitCode::FAILURE;
+        },
+    };
+
+    let Some(crop) = CropMode::parse(&args.crop) else {
+        println!("Unable to parse --crop {:?}; expected auto, off or W:H:X:Y.", args.crop);
+        return ExitCode::FAILURE;
+    };
+
+    let Some(dolby_vision) = DolbyVisionPolicy::parse(&args.dolby_vision) else {
+        println!("Unable to parse --dolby-vision {:?}; expected skip, base-layer or keep.", args.dolby_vision);
+        return ExitCode::FAILURE;
+    };
+
+    let tonemap = match (args.tonemap, TonemapAlgorithm::parse(&args.tonemap_algorithm)) {
+        (false, _) => None,
+        (true, Some(algorithm)) => Some(Tonemap { algorithm, peak: args.tonemap_peak }),
+        (true, None) => {
+            println!("Unable to parse --tonemap-algorithm {:?}; expected clip, linear, gamma, reinhard, hable or mobius.", args.tonemap_algorithm);
+            return ExitCode::FAILURE;
+        },
+    };
+
+    let Some(scaler) = Scaler::parse(&args.scaler) else {
+        println!("Unable to parse --scaler {:?}; expected bilinear, bicubic, lanczos, spline or area.", args.scaler);
+        return ExitCode::FAILURE;
+    };
+    let max_width = args.max_width.or(quality.max_width);
+    let max_height = args.max_height.or(quality.max_height);
+    if max_width.is_some_and(|width| width < 2) || max_height.is_some_and(|height| height < 2) {
+        println!("--max-width and --max-height must be at least 2.");
+        return ExitCode::FAILURE;
+    }
+
+    let film_grain = match args.film_grain.as_ref().map(|mode| (mode, FilmGrainMode::parse(mode))) {
+        Some((_, Some(mode))) => mode,
+        Some((mode, None)) => {
+            println!("Unable to parse --film-grain {:?}; expected auto, off or a level 0-50.", mode);
+            return ExitCode::FAILURE;
+        },
+        None => quality.film_grain().unwrap_or(FilmGrainMode::Auto),
+    };
+
+    let Some(content) = ContentMode::parse(&args.content) else {
+        println!("Unable to parse --content {:?}; expected auto, live-action or animation.", args.content);
+        return ExitCode::FAILURE;
+    };
+
+    let crf_table = match args.crf_table.as_ref().map(|table| (table, CrfTable::parse(table))) {
+        Some((_, Some(table))) => Some(table),
+        Some((table, None)) => {
+            println!("Unable to parse --crf-table {:?}; expected e.g. 480:20,720:23,1080:25,2160:29.", table);
+            return ExitCode::FAILURE;
+        },
+        None => None,
+    };
+
+    let codec = args.codec.to_lowercase();
+    match (codec.as_str(), &args.preset) {
+        ("av1", Some(preset)) => match preset.parse() {
+            Ok(preset) if SVTAV1_PRESETS.contains(&preset) => quality.av1.preset = preset,
+            _ => {
+                println!("Unable to parse --preset {:?}; expected -2 to 13 for av1.", preset);
+                return ExitCode::FAILURE;
+            },
+        },
+        ("hevc", Some(preset)) if X265_PRESETS.contains(&preset.as_str()) => quality.hevc.preset = preset.clone(),
+        ("hevc", Some(preset)) => {
+            println!("Unable to parse --preset {:?}; expected one of {} for hevc.", preset, X265_PRESETS.join(", "));
+            return ExitCode::FAILURE;
+        },
+        // unsupported codecs are reported for each file
+        _ => {},
+    }
+    let crf_range = match codec.as_str() {
+        "av1" => Some(av1::MIN_CRF..=av1::MAX_CRF),
+        "hevc" => Some(hevc::MIN_CRF..=hevc::MAX_CRF),
+        _ => None,
+    };
+    let max_crf = crf_range.as_ref().map(|range| *range.end());
+    if args.crf_step == 0 || max_crf.is_some_and(|max_crf| args.crf_step > max_crf) {
+        println!("--crf-step {} is out of range; expected 1 to {} for {}.", args.crf_step, max_crf.unwrap_or(u16::MAX), codec);
+        return ExitCode::FAILURE;
+    }
+    if let (Some(crf), Some(range)) = (args.crf, &crf_range) && !range.contains(&crf) {
+        println!("--crf {} is out of range; expected {} to {} for {}.", crf, range.start(), range.end(), codec);
+        return ExitCode::FAILURE;
+    }
+    let encoder_params = match (codec.as_str(), &args.svtav1_params, &args.x265_params) {
+        (_, None, None) => Vec::new(),
+        ("av1", Some(params), None) | ("hevc", None, Some(params)) => match parse_encoder_params(params) {
+            Some(params) => params,
+            None => {
+                println!("Unable to parse encoder parameters {:?}; expected key=value:key=value.", params);
+                return ExitCode::FAILURE;
+            },
+        },
+        _ => {
+            println!("--svtav1-params only applies to --codec av1, and --x265-params to --codec hevc.");
+            return ExitCode::FAILURE;
+        },
+    };
+    let ffmpeg_args: Vec<String> = args.ffmpeg_args.iter().flat_map(|args| args.split_whitespace()).map(String::from).collect();
+    if ffmpeg_args.first().is_some_and(|arg| !arg.starts_with('-')) {
+        println!("--ffmpeg-args must start with an option, e.g. \"-g 48\".");
+        return ExitCode::FAILURE;
+    }
+    if ffmpeg_args.iter().any(|arg| arg == "-f") {
+        println!("--ffmpeg-args can't set -f; the muxer follows --container.");
+        return ExitCode::FAILURE;
+    }
+
     let f = ffmpeg::FFmpeg::new(); 
     if !f.is_installed() {
         println!("ffmpeg is not installed.");
@@ -43,15 +323,57 @@ fn main() -> ExitCode {
     let rx = Rc::new(RefCell::new(rx));
     let compressor = Compressor::new(CompressorOptions {
         dry_run: args.dry_run,
-        fast: args.fast,
-        extreme: args.extreme,
+        sample: args.sample,
+        sample_count: args.sample_count,
+        sample_length: args.sample_length,
+        quality,
         overwrite: false,
-        codec: args.codec.to_lowercase(),
+        count_frames: args.count_frames,
+        codec,
         container: args.container.to_lowercase(),
-    }, Rc::clone(&rx));
+        max_size_ratio: args.max_size_ratio,
+        abort_after: args.abort_after,
+        retry_rejected: args.retry_rejected,
+        target_quality,
+        fallback_quality,
+        target_ratio: args.target_ratio,
+        max_attempts: args.max_attempts,
+        crf_step: args.crf_step,
+        max_crf: args.max_crf,
+        verify: args.verify,
+        verify_tolerance: args.verify_tolerance,
+        metrics: args.metrics,
+        deinterlace,
+        crop,
+        dolby_vision,
+        tonemap,
+        scale: Scale {
+            max_width,
+            max_height,
+            scaler,
+        },
+        film_grain,
+        film_grain_denoise: args.film_grain_denoise,
+        content,
+        crf_table,
+        overrides: EncoderOverrides {
+            crf: args.crf,
+            encoder_params,
+            ffmpeg_args,
+        },
+        replace: args.replace,
+        keep_name: args.keep_name,
+        archive_dir: args.archive_dir.as_ref().map(PathBuf::from),
+    }, Rc::clone(&rx), match (&probe_cache_path, args.probe_cache) {
+        (Some(path), true) => ProbeCache::open(path, args.hash_probe_cache),
+        _ => ProbeCache::in_memory(),
+    }, match (Ledger::default_path(), args.ledger) {
+        (Some(path), true) => Ledger::open(&path),
+        _ => Ledger::in_memory(),
+    });
 
     thread::spawn(move || {
-        if let Ok(mut signals) = Signals::new(&[SIGINT, SIGHUP, SIGTERM]) {
+        if let Ok(mut signals) = Signals::new([SIGINT, SIGHUP, SIGTERM]) {
             println!("Listening for SIGINT, SIGHUP, SIGTERM");
             for sig in signals.forever() {
                 match sig {
@@ -69,17 +391,44 @@ fn main() -> ExitCode {
         }
     });
 
+    // outputs are written beside their inputs or beneath --output-dir, so
+    // that's where interrupted runs leave their partials
+    let partial_dirs = args.infiles.iter().map(PathBuf::from).filter(|path| path.is_dir())
+        .chain(args.output_dir.as_ref().map(PathBuf::from));
+    for dir in partial_dirs {
+        for partial in find_stale_partials(&dir, args.recursive, &SUPPORTED_CODECS, &args.container.to_lowercase()) {
+            if !args.remove_partials || args.dry_run {
+                println!("Found stale partial {:?}.", partial);
+            } else {
+                println!("Removing stale partial {:?}.", partial);
+                if let Err(err) = remove_if_exists(&partial) {
+                    println!("Unable to remove stale partial {:?}; {}", partial, err);
+                }
+            }
+        }
+    }
+
     let mut exit_code = ExitCode::SUCCESS;
-    let rc_compressor = Rc::new(Box::new(compressor));
+    let rc_compressor = Rc::new(compressor);
     for infile in args.infiles {
         let handler = FilePathHandler::for_pathbuf(
             PathBuf::from(&infile),
-            FilePathHandlerOptions { recursive: args.recursive, },
+            FilePathHandlerOptions {
+                recursive: args.recursive,
+                output: args.output.as_ref().map(PathBuf::from),
+                output_dir: args.output_dir.as_ref().map(PathBuf::from),
+            },
             &Rc::clone(&rc_compressor));
         if handler.handle().is_err() {
             exit_code = ExitCode::FAILURE;
         }
+        if rc_compressor.stopped() {
+            exit_code = ExitCode::FAILURE;
+            break;
+        }
     }
+    rc_compressor.flush();
+    rc_compressor.print_summary();
 
     exit_code
 }